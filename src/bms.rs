@@ -0,0 +1,381 @@
+//! Export to BMS (Be-Music Source)
+//!
+//! Sounds are defined as `#WAVxx`, and notes are written in measure/channel lines. Lanes are
+//! mapped to 1P channels (ex. lane 0 to `11`) and lane 7 is the scratch (`16`). Lanes 8~15 are
+//! mapped to 2P channels in the same way.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+
+use crate::error::SmapError;
+use crate::types::chart::{
+    NOTE_HOLD_END, NOTE_HOLD_END_FLICK, NOTE_HOLD_START, NOTE_SLIDE_END, NOTE_SLIDE_END_FLICK,
+    NOTE_SLIDE_START,
+};
+use crate::types::soundmap::{Bpm, gcd};
+use crate::types::{Chart, Manifest, SoundMap};
+
+/// Channels of lanes 0~7 in 1P side. (Lane 7 is the scratch)
+const LANE_CHANNELS: [u8; 8] = [11, 12, 13, 14, 15, 18, 19, 16];
+
+const BGM_CHANNEL: u8 = 1;
+const MEASURE_LENGTH_CHANNEL: u8 = 2;
+const BPM_CHANNEL: u8 = 8;
+
+/// Offsets from a visible channel (ex. `11`) to other channels of the same lane.
+const INVISIBLE_OFFSET: u8 = 20;
+const LONG_NOTE_OFFSET: u8 = 40;
+
+/// An id of silent notes. (Notes which have no sound)
+const SILENT_ID: u16 = 36 * 36 - 1;
+
+/// Export a chart to a BMS file.
+///
+/// Soundmap notes which are not in the chart are written as BGM. Fake notes are written on
+/// invisible channels, and hold or slide notes on long note channels. (`#LNTYPE 1`)
+pub fn export_bms(
+    manifest: &Manifest,
+    soundmap: &SoundMap,
+    chart: &Chart,
+    path: &str,
+) -> Result<(), SmapError> {
+    fs::write(path, bms_string(manifest, soundmap, chart)?)?;
+    Ok(())
+}
+
+fn bms_string(
+    manifest: &Manifest,
+    soundmap: &SoundMap,
+    chart: &Chart,
+) -> Result<String, SmapError> {
+    if soundmap.note_tick == 0 {
+        return Err(SmapError::Export("Note tick is 0".to_string()));
+    }
+
+    // Events of (time, channel, id)
+    let mut events: Vec<(u32, u8, u16)> = Vec::new();
+
+    let mut chart_notes = HashSet::new();
+    for note in &chart.content {
+        let Some(time) = note.sound.resolve_time(soundmap) else {
+            continue;
+        };
+        if let Some(id) = note.sound.smap_note_id {
+            chart_notes.insert(id);
+        }
+        let id = match note.sound.resolve_sound(soundmap) {
+            Some(sound_id) => wav_id(sound_id)?,
+            None => SILENT_ID,
+        };
+
+        let mut channel = lane_channel(note.lane)?;
+        if note.fake {
+            channel += INVISIBLE_OFFSET;
+        } else if matches!(
+            note.note_type,
+            NOTE_HOLD_START
+                | NOTE_HOLD_END
+                | NOTE_HOLD_END_FLICK
+                | NOTE_SLIDE_START
+                | NOTE_SLIDE_END
+                | NOTE_SLIDE_END_FLICK
+        ) {
+            channel += LONG_NOTE_OFFSET;
+        }
+        events.push((time, channel, id));
+    }
+
+    for note in soundmap
+        .notes
+        .iter()
+        .filter(|n| !chart_notes.contains(&n.id))
+    {
+        events.push((note.time, BGM_CHANNEL, wav_id(note.sound_id)?));
+    }
+
+    let mut bpm: Vec<&Bpm> = soundmap.bpm.iter().collect();
+    bpm.sort_by_key(|b| b.time);
+    let default_bpm = Bpm::default();
+    let first_bpm = bpm
+        .first()
+        .filter(|b| b.time == 0)
+        .copied()
+        .unwrap_or(&default_bpm);
+    let bpm_changes: Vec<&Bpm> = bpm.into_iter().filter(|b| b.time > 0).collect();
+    for (index, change) in bpm_changes.iter().enumerate() {
+        events.push((change.time, BPM_CHANNEL, index as u16 + 1));
+    }
+
+    // Header
+    let mut bms = String::new();
+    writeln!(bms, "#PLAYER 1").unwrap();
+    writeln!(bms, "#GENRE {}", manifest.genre).unwrap();
+    writeln!(bms, "#TITLE {}", manifest.title).unwrap();
+    writeln!(bms, "#ARTIST {}", manifest.artists.join(" / ")).unwrap();
+    writeln!(bms, "#BPM {}", first_bpm.value).unwrap();
+    writeln!(bms, "#PLAYLEVEL {}", chart.difficulty_level).unwrap();
+    writeln!(bms, "#LNTYPE 1").unwrap();
+    writeln!(bms).unwrap();
+
+    let mut sounds: Vec<_> = manifest.sounds.iter().collect();
+    sounds.sort_by_key(|s| s.id);
+    for sound in sounds {
+        writeln!(bms, "#WAV{} {}", base36(wav_id(sound.id)?), sound.path).unwrap();
+    }
+    for (index, change) in bpm_changes.iter().enumerate() {
+        writeln!(bms, "#BPM{} {}", base36(index as u16 + 1), change.value).unwrap();
+    }
+    writeln!(bms).unwrap();
+
+    // Measures
+    let end = events.iter().map(|e| e.0).max().unwrap_or(0);
+    let boundaries = soundmap.measure_boundaries_until(end);
+    if boundaries.len() > 1000 {
+        return Err(SmapError::Export(format!(
+            "Too many measures: {}",
+            boundaries.len()
+        )));
+    }
+
+    let mut meters = soundmap.beat_per_bar.clone();
+    meters.sort_by_key(|b| b.time);
+    let four_beats = 4 * u32::from(soundmap.note_tick);
+
+    // (measure, channel) to events of (offset, id)
+    let mut lines: BTreeMap<(usize, u8), Vec<(u32, u16)>> = BTreeMap::new();
+    for (time, channel, id) in events {
+        let measure = boundaries.partition_point(|b| *b <= time) - 1;
+        lines
+            .entry((measure, channel))
+            .or_default()
+            .push((time - boundaries[measure], id));
+    }
+
+    for (measure, start) in boundaries.iter().enumerate() {
+        let length = match boundaries.get(measure + 1) {
+            Some(next) => next - start,
+            None => {
+                let beats = meters
+                    .iter()
+                    .rfind(|b| b.time <= *start)
+                    .map_or(4, |b| b.value);
+                u32::from(beats) * u32::from(soundmap.note_tick)
+            }
+        };
+
+        if length == 0 {
+            return Err(SmapError::Export(format!(
+                "Measure {measure} has no length"
+            )));
+        }
+        if length != four_beats {
+            writeln!(
+                bms,
+                "#{measure:03}{MEASURE_LENGTH_CHANNEL:02}:{}",
+                length as f64 / four_beats as f64
+            )
+            .unwrap();
+        }
+
+        for ((_, channel), events) in lines.range((measure, 0)..=(measure, u8::MAX)) {
+            for layer in layers(events) {
+                writeln!(
+                    bms,
+                    "#{measure:03}{channel:02}:{}",
+                    measure_data(&layer, length)
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    Ok(bms)
+}
+
+fn lane_channel(lane: u8) -> Result<u8, SmapError> {
+    match lane {
+        0..=7 => Ok(LANE_CHANNELS[lane as usize]),
+        8..=15 => Ok(LANE_CHANNELS[lane as usize - 8] + 10),
+        _ => Err(SmapError::Export(format!(
+            "Lane {lane} can't be mapped to a BMS channel"
+        ))),
+    }
+}
+
+/// Get an id of `#WAVxx` from a sound id. `00` is not used in BMS.
+fn wav_id(sound_id: u16) -> Result<u16, SmapError> {
+    if sound_id >= SILENT_ID - 1 {
+        return Err(SmapError::Export(format!(
+            "Sound {sound_id} can't be defined in BMS"
+        )));
+    }
+    Ok(sound_id + 1)
+}
+
+fn base36(id: u16) -> String {
+    const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let high = DIGITS[(id / 36) as usize] as char;
+    let low = DIGITS[(id % 36) as usize] as char;
+    format!("{high}{low}")
+}
+
+/// Split events, so each layer has only one event on a position.
+fn layers(events: &[(u32, u16)]) -> Vec<Vec<(u32, u16)>> {
+    let mut layers: Vec<Vec<(u32, u16)>> = Vec::new();
+    for event in events {
+        match layers
+            .iter_mut()
+            .find(|l| l.iter().all(|(offset, _)| *offset != event.0))
+        {
+            Some(layer) => layer.push(*event),
+            None => layers.push(vec![*event]),
+        }
+    }
+    layers
+}
+
+/// Write events of a measure in the smallest subdivision.
+fn measure_data(events: &[(u32, u16)], length: u32) -> String {
+    let step = events
+        .iter()
+        .fold(length, |step, (offset, _)| gcd(step, *offset));
+    let mut slots = vec![0; (length / step) as usize];
+    for (offset, id) in events {
+        slots[(offset / step) as usize] = *id;
+    }
+    slots.into_iter().map(base36).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch_dir;
+    use crate::types::chart::PlayNote;
+
+    /// Read note data lines of BMS, as (measure, channel, beat in the measure, id).
+    ///
+    /// This crate has no BMS importer, so the test can't check a full round trip. It is a
+    /// minimal reader which follows measure lengths (channel `02`), but not other headers.
+    fn read_notes(bms: &str) -> Vec<(u32, u8, f64, String)> {
+        let mut lengths: BTreeMap<u32, f64> = BTreeMap::new();
+        let mut lines = Vec::new();
+        for line in bms.lines() {
+            let Some((head, data)) = line.split_once(':') else {
+                continue;
+            };
+            let measure: u32 = head[1..4].parse().unwrap();
+            let channel: u8 = head[4..6].parse().unwrap();
+            if channel == MEASURE_LENGTH_CHANNEL {
+                lengths.insert(measure, data.parse().unwrap());
+            } else {
+                lines.push((measure, channel, data));
+            }
+        }
+
+        let mut notes = Vec::new();
+        for (measure, channel, data) in lines {
+            let beats = lengths.get(&measure).unwrap_or(&1.0) * 4.0;
+            let slots = data.len() / 2;
+            for slot in 0..slots {
+                let id = &data[slot * 2..slot * 2 + 2];
+                if id != "00" {
+                    let beat = slot as f64 / slots as f64 * beats;
+                    notes.push((measure, channel, beat, id.to_string()));
+                }
+            }
+        }
+        notes
+    }
+
+    #[test]
+    fn export_chart_to_bms() {
+        let dir = scratch_dir("export_bms");
+        let path = format!("{dir}/chart.bms");
+
+        let mut manifest = Manifest::new("Export", "Tester");
+        manifest.push_sound("kick.wav", 36);
+        manifest.push_sound("snare.wav", 38);
+
+        let mut soundmap = SoundMap::new();
+        soundmap.bpm.push(Bpm::new(150.0, 768));
+        soundmap.insert_note(0, 0, 0);
+        soundmap.insert_note(1, 96, 1);
+        soundmap.insert_note(0, 768 + 64, 0);
+        // BGM
+        soundmap.insert_note(1, 0, 1);
+
+        let mut chart = Chart::new("Normal", "Tester").with_level(3);
+        chart.insert_note(0, 0);
+        chart.insert_note(7, 1);
+        chart
+            .content
+            .push(PlayNote::new().with_lane(2).with_sound(2).fake());
+
+        export_bms(&manifest, &soundmap, &chart, &path).unwrap();
+        let bms = fs::read_to_string(&path).unwrap();
+
+        assert!(bms.contains("#TITLE Export\n"));
+        assert!(bms.contains("#BPM 120\n"));
+        assert!(bms.contains("#PLAYLEVEL 3\n"));
+        assert!(bms.contains("#WAV01 kick.wav\n"));
+        assert!(bms.contains("#WAV02 snare.wav\n"));
+        assert!(bms.contains("#BPM01 150\n"));
+
+        let notes = read_notes(&bms);
+        assert_eq!(
+            notes,
+            vec![
+                (0, 1, 0.0, "02".to_string()),
+                (0, 11, 0.0, "01".to_string()),
+                (0, 16, 0.5, "02".to_string()),
+                (1, 8, 0.0, "01".to_string()),
+                (1, 33, 1.0 / 3.0, "01".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn export_measure_length() {
+        let mut soundmap = SoundMap::new().with_beat_per_bar(3);
+        soundmap.insert_note(0, 192 * 2, 0);
+        soundmap.insert_note(0, 192 * 4, 0);
+        let bms = bms_string(
+            &Manifest::new("Export", "Tester"),
+            &soundmap,
+            &Chart::default(),
+        )
+        .unwrap();
+        assert!(bms.contains("#00002:0.75\n"));
+        assert_eq!(
+            read_notes(&bms),
+            vec![(0, 1, 2.0, "01".to_string()), (1, 1, 1.0, "01".to_string())]
+        );
+
+        // A bar without beats
+        let soundmap = SoundMap::new().with_beat_per_bar(0);
+        assert!(matches!(
+            bms_string(
+                &Manifest::new("Export", "Tester"),
+                &soundmap,
+                &Chart::default()
+            ),
+            Err(SmapError::Export(_))
+        ));
+    }
+
+    #[test]
+    fn export_unsupported_lane() {
+        let dir = scratch_dir("export_bms_lane");
+        let mut chart = Chart::new("Normal", "Tester");
+        chart.insert_silent_note(16, 0);
+
+        let result = export_bms(
+            &Manifest::new("Export", "Tester"),
+            &SoundMap::new(),
+            &chart,
+            &format!("{dir}/chart.bms"),
+        );
+        assert!(matches!(result, Err(SmapError::Export(_))));
+    }
+}
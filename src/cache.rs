@@ -0,0 +1,178 @@
+//! Thread-safe cache of loaded soundmaps
+//!
+//! `Manifest`, `SoundMap` and `Chart` are `Send + Sync`, so a loaded soundmap can be shared
+//! across threads with `Arc`.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+use crate::error::SmapError;
+use crate::load_smap_dir;
+use crate::types::{Chart, Manifest, SoundMap};
+
+/// A soundmap which loaded by `load_smap_dir`.
+pub type LoadedSmap = (Manifest, SoundMap, Vec<Chart>);
+
+// Fails to compile if a loaded soundmap can't be shared across threads.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<LoadedSmap>();
+    assert_send_sync::<SmapCache>();
+};
+
+/// A cache of soundmap directories.
+///
+/// Each path is loaded only once, and clones of `Arc` are handed out after. If threads miss the
+/// same path at once, one of them loads it and others wait for it. Loading a path doesn't block
+/// other paths.
+#[derive(Debug, Default)]
+pub struct SmapCache {
+    entries: RwLock<HashMap<PathBuf, Arc<CacheSlot>>>,
+    loads: AtomicUsize,
+}
+
+/// A slot of a path. It is locked while the path is loading, and `None` until it is loaded.
+type CacheSlot = Mutex<Option<Arc<LoadedSmap>>>;
+
+impl SmapCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get a soundmap from the cache, or load it if it isn't cached yet.
+    pub fn get_or_load(&self, smap_path: &str) -> Result<Arc<LoadedSmap>, SmapError> {
+        self.get_or_load_with(smap_path, load_smap_dir)
+    }
+
+    /// Same as `get_or_load`, but it loads a soundmap by `load`.
+    fn get_or_load_with(
+        &self,
+        smap_path: &str,
+        load: impl FnOnce(&str) -> Result<LoadedSmap, SmapError>,
+    ) -> Result<Arc<LoadedSmap>, SmapError> {
+        let key = PathBuf::from(smap_path);
+
+        let cached = self
+            .entries
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key)
+            .cloned();
+        let slot = match cached {
+            Some(slot) => slot,
+            None => Arc::clone(
+                self.entries
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .entry(key)
+                    .or_default(),
+            ),
+        };
+
+        // Only the slot is locked while loading, so other paths are not blocked
+        let mut slot = slot.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(loaded) = &*slot {
+            return Ok(Arc::clone(loaded));
+        }
+        let loaded = Arc::new(load(smap_path)?);
+        self.loads.fetch_add(1, Ordering::Relaxed);
+        *slot = Some(Arc::clone(&loaded));
+        Ok(loaded)
+    }
+
+    /// Remove a soundmap from the cache. It will be loaded again on next `get_or_load`.
+    pub fn invalidate(&self, smap_path: &str) {
+        self.entries
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&PathBuf::from(smap_path));
+    }
+
+    /// How many times soundmaps are actually loaded from disk.
+    pub fn load_count(&self) -> usize {
+        self.loads.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+    use crate::save_smap_dir;
+    use crate::tests::scratch_dir;
+
+    #[test]
+    fn load_once_from_threads() {
+        let dir = scratch_dir("cache");
+        let charts: Vec<Chart> = Vec::new();
+        save_smap_dir(
+            "cached",
+            &dir,
+            &Manifest::new("Cached", "Various Artists"),
+            &SoundMap::new(),
+            &charts,
+        )
+        .unwrap();
+        let smap_path = format!("{dir}/cached");
+
+        let cache = SmapCache::new();
+        let (first, second) = thread::scope(|s| {
+            let first = s.spawn(|| cache.get_or_load(&smap_path).unwrap());
+            let second = s.spawn(|| cache.get_or_load(&smap_path).unwrap());
+            (first.join().unwrap(), second.join().unwrap())
+        });
+
+        assert_eq!(cache.load_count(), 1);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.0.title, "Cached");
+
+        // Load again after invalidation
+        cache.invalidate(&smap_path);
+        cache.get_or_load(&smap_path).unwrap();
+        assert_eq!(cache.load_count(), 2);
+    }
+
+    #[test]
+    fn read_while_loading() {
+        let dir = scratch_dir("cache_read_while_loading");
+        let manifest = Manifest::new("Cached", "Various Artists");
+        save_smap_dir("cached", &dir, &manifest, &SoundMap::new(), &[]).unwrap();
+        let smap_path = format!("{dir}/cached");
+
+        let cache = SmapCache::new();
+        let cached = cache.get_or_load(&smap_path).unwrap();
+
+        // The slow load waits until the cached one is read
+        let (started_sender, started) = mpsc::channel();
+        let (read_sender, read) = mpsc::channel();
+        thread::scope(|s| {
+            let slow = s.spawn(|| {
+                cache.get_or_load_with("slow", move |_| {
+                    started_sender.send(()).unwrap();
+                    read.recv().unwrap();
+                    Ok((manifest.clone(), SoundMap::new(), Vec::new()))
+                })
+            });
+
+            started.recv().unwrap();
+            // Another miss of the loading path waits for it, instead of loading it again
+            let waiting = s.spawn(|| {
+                cache.get_or_load_with("slow", |_| Err(SmapError::Check("Loaded again".into())))
+            });
+            assert!(Arc::ptr_eq(
+                &cached,
+                &cache.get_or_load(&smap_path).unwrap()
+            ));
+            read_sender.send(()).unwrap();
+
+            let slow = slow.join().unwrap().unwrap();
+            assert_eq!(slow.0.title, "Cached");
+            assert!(Arc::ptr_eq(&slow, &waiting.join().unwrap().unwrap()));
+        });
+        assert_eq!(cache.load_count(), 2);
+    }
+}
@@ -0,0 +1,642 @@
+//! Check soundmap directory
+//!
+//! Hard failures (ex. missing manifest) are errors, and semantic problems which still can be
+//! loaded (ex. unused sounds) are warnings.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::error::SmapError;
+use crate::types::soundmap::ValidatedSoundMap;
+use crate::types::{Chart, Manifest, SoundMap};
+use crate::{
+    chart_file_name, chart_files, files_in, other_chart_dir_files, read_json, read_json_file,
+};
+
+/// An issue which found by `inspect_smap`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckIssue {
+    /// A file which has the issue, relative to the soundmap directory.
+    pub file: Option<PathBuf>,
+
+    pub message: String,
+}
+
+impl CheckIssue {
+    fn new(file: Option<&Path>, message: String) -> Self {
+        Self {
+            file: file.map(Path::to_path_buf),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for CheckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}: {}", file.display(), self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// A result of `inspect_smap`.
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    pub errors: Vec<CheckIssue>,
+    pub warnings: Vec<CheckIssue>,
+}
+
+impl CheckReport {
+    /// Whether there are no errors. Warnings are allowed.
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    fn error(&mut self, file: Option<&Path>, message: String) {
+        self.errors.push(CheckIssue::new(file, message));
+    }
+
+    fn warn(&mut self, file: Option<&Path>, message: String) {
+        self.warnings.push(CheckIssue::new(file, message));
+    }
+}
+
+/// Check soundmap directory
+///
+/// It returns the first error of `inspect_smap`, with its file. Warnings are ignored.
+pub fn check_smap(smap_path: &str) -> Result<(), SmapError> {
+    match inspect_smap(smap_path).errors.into_iter().next() {
+        Some(error) => Err(SmapError::Check(error.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Check soundmap directory, and report errors and warnings.
+pub fn inspect_smap(smap_path: &str) -> CheckReport {
+    let mut report = CheckReport::default();
+
+    let manifest_file = Path::new("manifest.json");
+    let manifest = match read_json_file(Path::new(smap_path).join(manifest_file)) {
+        Ok(m) => match serde_json::from_str::<Manifest>(&m) {
+            Ok(manifest) => Some(manifest),
+            Err(e) => {
+                report.error(
+                    Some(manifest_file),
+                    format!("Failed to parse manifest: {e}"),
+                );
+                None
+            }
+        },
+        Err(e) => {
+            report.error(Some(manifest_file), format!("Failed to read manifest: {e}"));
+            None
+        }
+    };
+
+    let soundmap_file = Path::new("content.json");
+    let soundmap = match read_json_file(Path::new(smap_path).join(soundmap_file)) {
+        Ok(s) => match serde_json::from_str::<ValidatedSoundMap>(&s) {
+            Ok(ValidatedSoundMap(soundmap)) => Some(soundmap),
+            Err(e) => {
+                report.error(
+                    Some(soundmap_file),
+                    format!("Failed to parse soundmap: {e}"),
+                );
+                None
+            }
+        },
+        Err(e) => {
+            report.error(Some(soundmap_file), format!("Failed to read soundmap: {e}"));
+            None
+        }
+    };
+
+    let mut charts = Vec::new();
+    let charts_dir = Path::new(smap_path).join("charts");
+    if charts_dir.exists() {
+        match chart_files(&charts_dir) {
+            Ok(paths) => {
+                for path in paths {
+                    let file = path.strip_prefix(smap_path).unwrap_or(&path).to_path_buf();
+                    match read_json_file(&path) {
+                        Ok(c) => match serde_json::from_str::<Chart>(&c) {
+                            Ok(chart) => charts.push((file, chart)),
+                            Err(e) => {
+                                report.error(Some(&file), format!("Failed to parse chart: {e}"))
+                            }
+                        },
+                        Err(e) => report.error(Some(&file), format!("Failed to read chart: {e}")),
+                    }
+                }
+            }
+            Err(e) => report.error(None, format!("Failed to read charts directory: {e}")),
+        }
+        if let Ok(paths) = other_chart_dir_files(&charts_dir) {
+            for path in paths {
+                let file = path.strip_prefix(smap_path).unwrap_or(&path);
+                report.warn(
+                    Some(file),
+                    "File is not a chart, and it is ignored".to_string(),
+                );
+            }
+        }
+    } else {
+        report.error(None, "Cannot find charts directory".to_string());
+    }
+
+    if let Some(manifest) = &manifest {
+        check_manifest(&mut report, manifest_file, manifest);
+    }
+    if let Some(soundmap) = &soundmap {
+        check_soundmap(&mut report, soundmap_file, soundmap);
+    }
+    for (file, chart) in &charts {
+        check_chart(&mut report, file, chart);
+    }
+    if let Some(soundmap) = &soundmap {
+        for (file, chart) in &charts {
+            if let Err(missing) = chart.validate_against(soundmap) {
+                report.error(
+                    Some(file),
+                    format!("Chart references missing soundmap notes: {missing:?}"),
+                );
+            }
+        }
+    }
+    if let (Some(manifest), Some(soundmap)) = (&manifest, &soundmap) {
+        check_extensions(&mut report, manifest_file, manifest, soundmap);
+        check_sounds_usage(&mut report, manifest_file, manifest, soundmap);
+    }
+
+    report
+}
+
+/// Check sound files of the manifest are in `sounds/`.
+///
+/// It returns every missing sound file in the error. Orphan files are warnings of
+/// `inspect_smap_assets`, so they are ignored.
+pub fn check_smap_assets(smap_path: &str) -> Result<(), SmapError> {
+    let report = inspect_smap_assets(smap_path);
+    if report.is_ok() {
+        return Ok(());
+    }
+    let messages: Vec<String> = report.errors.into_iter().map(|e| e.message).collect();
+    Err(SmapError::Check(messages.join(", ")))
+}
+
+/// Check sound files of the manifest, and report errors and warnings.
+///
+/// Sounds which are not in `sounds/` are errors, and files in `sounds/` which no sound
+/// references are warnings. It is not a part of `inspect_smap`, because sounds are often added
+/// after charts while making a soundmap.
+pub fn inspect_smap_assets(smap_path: &str) -> CheckReport {
+    let mut report = CheckReport::default();
+
+    let manifest_file = Path::new("manifest.json");
+    let manifest: Manifest = match read_json(&Path::new(smap_path).join(manifest_file)) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            report.error(Some(manifest_file), format!("Failed to read manifest: {e}"));
+            return report;
+        }
+    };
+
+    let sounds_dir = Path::new(smap_path).join("sounds");
+    for sound in &manifest.sounds {
+        if !sounds_dir.join(&sound.path).is_file() {
+            report.error(
+                Some(manifest_file),
+                format!("Cannot find file of sound {} (`{}`)", sound.id, sound.path),
+            );
+        }
+    }
+
+    if sounds_dir.is_dir() {
+        match files_in(&sounds_dir) {
+            Ok(paths) => {
+                for path in paths {
+                    if !manifest
+                        .sounds
+                        .iter()
+                        .any(|s| sounds_dir.join(&s.path) == path)
+                    {
+                        let file = path.strip_prefix(smap_path).unwrap_or(&path);
+                        report.warn(
+                            Some(file),
+                            "File is not referenced by any sound".to_string(),
+                        );
+                    }
+                }
+            }
+            Err(e) => report.error(None, format!("Failed to read sounds directory: {e}")),
+        }
+    }
+
+    report
+}
+
+fn check_manifest(report: &mut CheckReport, file: &Path, manifest: &Manifest) {
+    // A package with a source URL is distributed
+    if manifest.source_url.is_some() && manifest.license.is_none() {
+        report.warn(
+            Some(file),
+            "Manifest has a source URL, but no license".to_string(),
+        );
+    }
+    for sound in manifest.sounds.iter().filter(|s| !s.has_valid_cents()) {
+        report.warn(
+            Some(file),
+            format!(
+                "Cents of sound {} is out of range: {:?}",
+                sound.id, sound.cents
+            ),
+        );
+    }
+    for sound in manifest
+        .sounds
+        .iter()
+        .filter(|s| !s.has_valid_loop_points())
+    {
+        report.warn(
+            Some(file),
+            format!(
+                "Loop points of sound {} are invalid: {:?} ~ {:?}",
+                sound.id, sound.loop_start, sound.loop_end
+            ),
+        );
+    }
+}
+
+fn check_soundmap(report: &mut CheckReport, file: &Path, soundmap: &SoundMap) {
+    if soundmap.bpm.is_empty() {
+        report.warn(
+            Some(file),
+            "Soundmap has no BPM, so 120 BPM is used".to_string(),
+        );
+    }
+    let defaults: Vec<u16> = soundmap
+        .suspicious_default_notes()
+        .iter()
+        .map(|n| n.id)
+        .collect();
+    if !defaults.is_empty() {
+        report.warn(
+            Some(file),
+            format!("Notes look like default values (sound 0, time 0, track 0): {defaults:?}"),
+        );
+    }
+    let untagged = soundmap.untagged_tracks();
+    if !untagged.is_empty() {
+        report.warn(Some(file), format!("Tracks without tags: {untagged:?}"));
+    }
+}
+
+fn check_chart(report: &mut CheckReport, file: &Path, chart: &Chart) {
+    if chart
+        .scroll_velocities
+        .windows(2)
+        .any(|pair| pair[0].time > pair[1].time)
+    {
+        report.warn(
+            Some(file),
+            format!(
+                "Scroll velocities of chart `{}` are not sorted by time",
+                chart.name
+            ),
+        );
+    }
+    for sv in chart
+        .scroll_velocities
+        .iter()
+        .filter(|sv| sv.multiplier <= 0.0)
+    {
+        report.warn(
+            Some(file),
+            format!(
+                "Scroll velocity at {} is not positive: {}",
+                sv.time, sv.multiplier
+            ),
+        );
+    }
+    if let Err(e) = chart.validate_level() {
+        report.warn(Some(file), e.to_string());
+    }
+    let expected = chart_file_name(chart);
+    if file.file_name().is_some_and(|name| *name != *expected) {
+        report.warn(
+            Some(file),
+            format!(
+                "File name doesn't match chart `{}`, expected `{expected}`",
+                chart.name
+            ),
+        );
+    }
+    if chart.has_unknown_type() {
+        report.warn(
+            Some(file),
+            format!(
+                "Unknown chart type `{}` in chart `{}`",
+                chart.chart_type, chart.name
+            ),
+        );
+    }
+}
+
+fn check_extensions(
+    report: &mut CheckReport,
+    file: &Path,
+    manifest: &Manifest,
+    soundmap: &SoundMap,
+) {
+    for mismatch in manifest.validate_extensions(soundmap) {
+        report.warn(
+            Some(file),
+            format!(
+                "Sound {} (`{}`) doesn't match the audio format `{}`. (Expected {:?})",
+                mismatch.sound_id, mismatch.path, mismatch.audio_format, mismatch.expected
+            ),
+        );
+    }
+}
+
+fn check_sounds_usage(
+    report: &mut CheckReport,
+    file: &Path,
+    manifest: &Manifest,
+    soundmap: &SoundMap,
+) {
+    for sound in &manifest.sounds {
+        if !soundmap.notes.iter().any(|n| n.sound_id == sound.id) {
+            report.warn(
+                Some(file),
+                format!(
+                    "Sound {} (`{}`) is not used by any note",
+                    sound.id, sound.path
+                ),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::save_smap_dir;
+    use crate::tests::scratch_dir;
+    use crate::types::chart::ScrollVelocity;
+    use crate::types::soundmap::Instrument;
+
+    fn save(dir: &str, manifest: &Manifest, soundmap: &SoundMap, charts: &[Chart]) -> String {
+        save_smap_dir("smap", dir, manifest, soundmap, charts).unwrap();
+        format!("{dir}/smap")
+    }
+
+    #[test]
+    fn report_unknown_chart_type() {
+        let dir = scratch_dir("check_chart_type");
+        let charts = vec![
+            Chart::new("Known", "Tester").with_chart_type("7k"),
+            Chart::new("Unknown", "Tester").with_chart_type("Drum"),
+            Chart::new("Custom", "Tester").with_custom_type("Drum"),
+        ];
+        let smap_path = save(
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &SoundMap::new(),
+            &charts,
+        );
+
+        let report = inspect_smap(&smap_path);
+        assert!(report.is_ok());
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].message.contains("Unknown"));
+        assert_eq!(
+            report.warnings[0].file.as_deref(),
+            Some(Path::new("charts/Unknown.json"))
+        );
+    }
+
+    #[test]
+    fn report_untagged_tracks() {
+        let dir = scratch_dir("check_untagged_tracks");
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(0, 0, 3);
+        let smap_path = save(
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &soundmap,
+            &[],
+        );
+
+        let report = inspect_smap(&smap_path);
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.warnings[0].message, "Tracks without tags: [3]");
+    }
+
+    #[test]
+    fn report_default_notes() {
+        let dir = scratch_dir("check_default_notes");
+        let mut soundmap = SoundMap::new();
+        for _ in 0..3 {
+            soundmap.insert_note(0, 0, 0);
+        }
+        soundmap.set_note_track(0, "Kick", Instrument::Kick);
+        let smap_path = save(
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &soundmap,
+            &[],
+        );
+
+        let report = inspect_smap(&smap_path);
+        assert!(report.is_ok());
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].message.ends_with("[0, 1, 2]"));
+    }
+
+    #[test]
+    fn report_only_warnings() {
+        let dir = scratch_dir("check_only_warnings");
+        let mut manifest = Manifest::new("Test", "Various Artists");
+        manifest.push_sound("unused.wav", 60);
+        let smap_path = save(&dir, &manifest, &SoundMap::new(), &[]);
+
+        let report = inspect_smap(&smap_path);
+        assert!(report.errors.is_empty());
+        assert!(report.is_ok());
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].message.contains("unused.wav"));
+        check_smap(&smap_path).unwrap();
+    }
+
+    #[test]
+    fn report_missing_license() {
+        let dir = scratch_dir("check_license");
+        let manifest =
+            Manifest::new("Test", "Various Artists").with_source_url("https://example.com/test");
+        let smap_path = save(&dir, &manifest, &SoundMap::new(), &[]);
+
+        let report = inspect_smap(&smap_path);
+        assert!(report.is_ok());
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].message.contains("no license"));
+
+        let dir = scratch_dir("check_license_set");
+        let smap_path = save(
+            &dir,
+            &manifest.with_license("CC-BY-4.0"),
+            &SoundMap::new(),
+            &[],
+        );
+        assert!(inspect_smap(&smap_path).warnings.is_empty());
+    }
+
+    #[test]
+    fn report_extension_mismatch() {
+        let dir = scratch_dir("check_extension");
+        let mut manifest = Manifest::new("Test", "Various Artists");
+        manifest.push_sound("kick.wav", 36);
+        let mut soundmap = SoundMap::new().with_audio_format("ogg");
+        soundmap.insert_note(0, 0, 0);
+        soundmap.set_note_track(0, "Kick", Instrument::Kick);
+        let smap_path = save(&dir, &manifest, &soundmap, &[]);
+
+        // It is a warning, so the check passes
+        check_smap(&smap_path).unwrap();
+        let report = inspect_smap(&smap_path);
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].message.contains("kick.wav"));
+        assert!(report.warnings[0].message.contains("ogg"));
+    }
+
+    #[test]
+    fn report_scroll_velocities() {
+        let dir = scratch_dir("check_scroll_velocities");
+        let mut chart = Chart::new("Normal", "Tester").with_chart_type("4k");
+        chart.scroll_velocities = vec![ScrollVelocity::new(768, 1.0), ScrollVelocity::new(0, 0.0)];
+        let smap_path = save(
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &SoundMap::new(),
+            &[chart],
+        );
+
+        let report = inspect_smap(&smap_path);
+        assert!(report.is_ok());
+        assert_eq!(report.warnings.len(), 2);
+        assert!(report.warnings[0].message.contains("not sorted"));
+        assert!(report.warnings[1].message.contains("not positive"));
+    }
+
+    #[test]
+    fn report_level_out_of_range() {
+        let dir = scratch_dir("check_level");
+        let too_high = Chart::new("Joke", "Tester")
+            .with_chart_type("7K")
+            .with_level(99);
+        assert!(matches!(
+            too_high.validate_level(),
+            Err(SmapError::LevelOutOfRange {
+                level: 99,
+                max: 20,
+                ..
+            })
+        ));
+        assert!(too_high.clone().ignore_max_level().validate_level().is_ok());
+
+        let smap_path = save(
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &SoundMap::new(),
+            &[
+                too_high,
+                Chart::new("Normal", "Tester").with_chart_type("7K"),
+            ],
+        );
+        let report = inspect_smap(&smap_path);
+        assert!(report.is_ok());
+        assert_eq!(report.warnings.len(), 1);
+        assert!(
+            report.warnings[0]
+                .message
+                .contains("Level 99 of chart `Joke`")
+        );
+    }
+
+    #[test]
+    fn report_missing_soundmap_notes() {
+        let dir = scratch_dir("check_missing_soundmap_notes");
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(0, 0, 0);
+        soundmap.set_note_track(0, "Kick", Instrument::Kick);
+        let mut chart = Chart::new("Broken", "Tester").with_chart_type("4K");
+        chart.insert_note(0, 0);
+        chart.insert_note(1, 7);
+        let smap_path = save(
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &soundmap,
+            &[chart],
+        );
+
+        let report = inspect_smap(&smap_path);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(
+            report.errors[0].file.as_deref(),
+            Some(Path::new("charts/Broken.json"))
+        );
+        let error = check_smap(&smap_path).unwrap_err().to_string();
+        assert!(error.contains("charts/Broken.json: "));
+        assert!(error.ends_with("[7]"));
+    }
+
+    #[test]
+    fn report_sound_files() {
+        let dir = scratch_dir("check_sound_files");
+        let mut manifest = Manifest::new("Test", "Various Artists");
+        manifest.push_sound("kick.wav", 36);
+        manifest.push_sound("drums/snare.wav", 38);
+        manifest.push_sound("missing.wav", 42);
+        let smap_path = save(&dir, &manifest, &SoundMap::new(), &[]);
+        fs::create_dir(format!("{smap_path}/sounds/drums")).unwrap();
+        for file in ["kick.wav", "drums/snare.wav", "orphan.wav"] {
+            fs::write(format!("{smap_path}/sounds/{file}"), b"RIFF").unwrap();
+        }
+
+        let report = inspect_smap_assets(&smap_path);
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].message.contains("missing.wav"));
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(
+            report.warnings[0].file.as_deref(),
+            Some(Path::new("sounds/orphan.wav"))
+        );
+        assert!(
+            check_smap_assets(&smap_path)
+                .unwrap_err()
+                .to_string()
+                .contains("missing.wav")
+        );
+
+        fs::write(format!("{smap_path}/sounds/missing.wav"), b"RIFF").unwrap();
+        check_smap_assets(&smap_path).unwrap();
+    }
+
+    #[test]
+    fn report_errors() {
+        let dir = scratch_dir("check_errors");
+        let report = inspect_smap(&dir);
+        assert!(!report.is_ok());
+        // Manifest, soundmap and charts directory
+        assert_eq!(report.errors.len(), 3);
+        assert!(
+            check_smap(&dir)
+                .unwrap_err()
+                .to_string()
+                .contains("manifest")
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Error types
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// An error while handling soundmap files.
+#[derive(Debug)]
+pub enum SmapError {
+    /// An I/O error
+    Io(io::Error),
+
+    /// Failed to parse a JSON file
+    Json {
+        file: PathBuf,
+        source: serde_json::Error,
+    },
+
+    /// A soundmap directory has no `manifest.json`.
+    MissingManifest,
+
+    /// A soundmap directory has no `content.json`.
+    MissingSoundmap,
+
+    /// A soundmap directory has no `charts` directory.
+    MissingChartsDir,
+
+    /// A file would overwrite another file.
+    FileCollision(PathBuf),
+
+    /// A soundmap directory already exists. (See `save_smap_dir_overwrite`)
+    AlreadyExists(PathBuf),
+
+    /// A soundmap directory is invalid. (See `check_smap`)
+    Check(String),
+
+    /// A lz4 compression level is out of range. (See `PACK_LEVELS`)
+    InvalidLevel(u32),
+
+    /// A `*.smap` archive is truncated or corrupted.
+    InvalidArchive(String),
+
+    /// Cannot find a chart which has the name.
+    ChartNotFound(String),
+
+    /// Base charts reference each other. (See `Chart::resolve_base`)
+    ChartCycle(String),
+
+    /// Failed to download a soundmap. (See `load_smap_url`)
+    Download(String),
+
+    /// An archive has more entries than the limit. (See `unpack_with_limits`)
+    TooManyEntries(usize),
+
+    /// An archive is larger than the limit in bytes. (See `unpack_with_limits`)
+    TooLarge(u64),
+
+    /// A JSON file is empty or has only whitespaces.
+    EmptyFile(PathBuf),
+
+    /// A soundmap can't be exported to other format. (ex. BMS)
+    Export(String),
+
+    /// A difficulty level is higher than the maximum of the chart type.
+    /// (See `Chart::validate_level`)
+    LevelOutOfRange { chart: String, level: u8, max: u8 },
+
+    /// A soundmap breaks its invariants. (See `SoundMap::validate_and_normalize`)
+    InvalidSoundmap(String),
+
+    /// A chart can't be handled. (ex. Unpaired hold notes, missing soundmap notes)
+    InvalidChart(String),
+
+    /// A note would be on the same time with another note. (See `Chart::move_lane_checked`)
+    LaneCollision { lane: u8, time: u32 },
+
+    /// Loop start of a sound is not before loop end. (See `Sound::with_loop_points`)
+    InvalidLoopPoints { start: u32, end: u32 },
+}
+
+impl fmt::Display for SmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+            Self::Json { file, source } => {
+                write!(f, "Failed to parse {}: {source}", file.display())
+            }
+            Self::MissingManifest => write!(f, "Cannot find manifest.json"),
+            Self::MissingSoundmap => write!(f, "Cannot find content.json"),
+            Self::MissingChartsDir => write!(f, "Cannot find charts directory"),
+            Self::FileCollision(path) => {
+                write!(f, "File would be overwritten: {}", path.display())
+            }
+            Self::AlreadyExists(path) => write!(f, "Already exists: {}", path.display()),
+            Self::Check(message) => write!(f, "Invalid soundmap: {message}"),
+            Self::InvalidLevel(level) => write!(f, "Invalid compression level: {level}"),
+            Self::InvalidArchive(message) => write!(f, "Invalid archive: {message}"),
+            Self::ChartNotFound(name) => write!(f, "Cannot find chart `{name}`"),
+            Self::ChartCycle(name) => write!(f, "Base of chart `{name}` references itself"),
+            Self::Download(message) => write!(f, "Failed to download: {message}"),
+            Self::TooManyEntries(max) => write!(f, "Archive has more than {max} entries"),
+            Self::TooLarge(max) => write!(f, "Archive is larger than {max} bytes"),
+            Self::EmptyFile(path) => write!(f, "File is empty: {}", path.display()),
+            Self::Export(message) => write!(f, "Failed to export: {message}"),
+            Self::LevelOutOfRange { chart, level, max } => {
+                write!(f, "Level {level} of chart `{chart}` is higher than {max}")
+            }
+            Self::InvalidSoundmap(message) => write!(f, "Invalid soundmap content: {message}"),
+            Self::InvalidChart(message) => write!(f, "Invalid chart: {message}"),
+            Self::LaneCollision { lane, time } => {
+                write!(f, "Note collides on lane {lane} at {time}")
+            }
+            Self::InvalidLoopPoints { start, end } => {
+                write!(f, "Loop start {start} must be before loop end {end}")
+            }
+        }
+    }
+}
+
+impl Error for SmapError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Json { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SmapError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
@@ -1,312 +1,1579 @@
+pub mod bms;
+pub mod cache;
+pub mod check;
+pub mod error;
+mod hash;
+pub mod library;
+pub mod package;
 pub mod types;
 
+use cache::LoadedSmap;
 use lz4::{Decoder, EncoderBuilder};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io;
-use std::path::Path;
+use std::hash::Hasher;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::path::{Component, Path, PathBuf};
+use types::soundmap::ValidatedSoundMap;
 use types::{Chart, Manifest, SoundMap};
 
+pub use bms::export_bms;
+pub use cache::SmapCache;
+pub use check::{
+    CheckIssue, CheckReport, check_smap, check_smap_assets, inspect_smap, inspect_smap_assets,
+};
+pub use error::SmapError;
+pub use library::{build_library_index, load_library_index, search_index};
+pub use package::{
+    normalize_package, package_note_type_histogram, package_sound_reuse, package_used_lanes,
+    unused_after_chart_removal,
+};
+
 /// Load soundmap format files.
-pub fn load_smap_dir(smap_path: &str) -> io::Result<(Manifest, SoundMap, Vec<Chart>)> {
+///
+/// The soundmap is validated and normalized while loading. (See `SoundMap::validate_and_normalize`)
+/// If it has no BPMs, 120 BPM is inserted. (See `SoundMap::ensure_tempo`)
+pub fn load_smap_dir(smap_path: &str) -> Result<(Manifest, SoundMap, Vec<Chart>), SmapError> {
+    Ok(load_smap_dir_with(smap_path, true)?.0)
+}
+
+/// Same as `load_smap_dir`, but it also returns chart files which don't match their `name`
+/// field, as `(current path, expected path)`. (See `check_chart_filenames`)
+pub fn load_smap_dir_with_report(
+    smap_path: &str,
+) -> Result<(LoadedSmap, Vec<(PathBuf, PathBuf)>), SmapError> {
+    load_smap_dir_with(smap_path, true)
+}
+
+/// Load soundmap format files as it is, without validation of the soundmap.
+pub fn load_smap_dir_raw(smap_path: &str) -> Result<(Manifest, SoundMap, Vec<Chart>), SmapError> {
+    Ok(load_smap_dir_with(smap_path, false)?.0)
+}
+
+fn load_smap_dir_with(
+    smap_path: &str,
+    validate: bool,
+) -> Result<(LoadedSmap, Vec<(PathBuf, PathBuf)>), SmapError> {
+    let smap_path = Path::new(smap_path);
+    let manifest_path = smap_path.join("manifest.json");
+    let soundmap_path = smap_path.join("content.json");
+    let charts_dir = smap_path.join("charts");
+    if !manifest_path.is_file() {
+        return Err(SmapError::MissingManifest);
+    }
+    if !soundmap_path.is_file() {
+        return Err(SmapError::MissingSoundmap);
+    }
+    if !charts_dir.is_dir() {
+        return Err(SmapError::MissingChartsDir);
+    }
+
     // Load manifest
-    let manifest_path = format!("{smap_path}/manifest.json");
-    let manifest = fs::read_to_string(&manifest_path)?;
-    let manifest: Manifest = serde_json::from_str(&manifest)?;
+    let manifest: Manifest = read_json(&manifest_path)?;
 
     // Load soundmap
-    let soundmap_path = format!("{smap_path}/content.json");
-    let soundmap = fs::read_to_string(&soundmap_path)?;
-    let soundmap: SoundMap = serde_json::from_str(&soundmap)?;
+    let soundmap: SoundMap = if validate {
+        let mut soundmap = read_json::<ValidatedSoundMap>(&soundmap_path)?.into_inner();
+        soundmap.ensure_tempo();
+        soundmap
+    } else {
+        read_json(&soundmap_path)?
+    };
 
     // Load charts
-    let charts_dir = format!("{smap_path}/charts");
     let mut charts = Vec::new();
-    for entry in fs::read_dir(&charts_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_file() {
-            let chart = fs::read_to_string(path)?;
-            let chart: Chart = serde_json::from_str(&chart)?;
-            charts.push(chart);
+    let mut mismatches = Vec::new();
+    for path in chart_files(&charts_dir)? {
+        let chart: Chart = read_json(&path)?;
+        mismatches.extend(chart_filename_mismatch(&path, &chart));
+        charts.push(chart);
+    }
+
+    Ok(((manifest, soundmap, charts), mismatches))
+}
+
+/// Read a JSON file as UTF-8. A leading BOM is removed.
+///
+/// UTF-16 files are rejected with a message to re-encode them. Empty files are rejected with
+/// `SmapError::EmptyFile`.
+fn read_json_file<P: AsRef<Path>>(path: P) -> Result<String, SmapError> {
+    let path = path.as_ref();
+    decode_json(fs::read(path)?, path)
+}
+
+/// Read and parse a JSON file. (See `read_json_file`)
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, SmapError> {
+    let json = read_json_file(path)?;
+    serde_json::from_str(&json).map_err(|source| SmapError::Json {
+        file: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Serialize a value to pretty JSON. `file` is used for error messages.
+fn to_json<T: Serialize>(value: &T, file: &Path) -> Result<String, SmapError> {
+    serde_json::to_string_pretty(value).map_err(|source| SmapError::Json {
+        file: file.to_path_buf(),
+        source,
+    })
+}
+
+/// Same as `read_json_file`, but from bytes. `path` is used for error messages.
+fn decode_json(bytes: Vec<u8>, path: &Path) -> Result<String, SmapError> {
+    if bytes.starts_with(&[0xFF, 0xFE]) || bytes.starts_with(&[0xFE, 0xFF]) {
+        return Err(SmapError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} is encoded in UTF-16. Please re-encode it in UTF-8",
+                path.display()
+            ),
+        )));
+    }
+
+    let bytes = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]).unwrap_or(&bytes);
+    let json = String::from_utf8(bytes.to_vec()).map_err(|e| {
+        SmapError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not valid UTF-8: {e}", path.display()),
+        ))
+    })?;
+
+    if json.trim().is_empty() {
+        return Err(SmapError::EmptyFile(path.to_path_buf()));
+    }
+    Ok(json)
+}
+
+/// Load a `*.smap` file in memory, without writing any files.
+///
+/// The soundmap is validated and normalized while loading. (Same as `load_smap_dir`)
+pub fn load_smap_bytes(smap: &[u8]) -> Result<(Manifest, SoundMap, Vec<Chart>), SmapError> {
+    let invalid = |e: io::Error| SmapError::InvalidArchive(e.to_string());
+    let parse_error = |file: &Path| {
+        let file = file.to_path_buf();
+        move |source| SmapError::Json { file, source }
+    };
+
+    let mut archive = tar::Archive::new(Decoder::new(smap).map_err(invalid)?);
+    let mut manifest = None;
+    let mut soundmap = None;
+    let mut charts = Vec::new();
+    for entry in archive.entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+        let path = entry.path().map_err(invalid)?.to_path_buf();
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let is_chart = path.starts_with("charts") && is_chart_file(&path);
+        if path != Path::new("manifest.json") && path != Path::new("content.json") && !is_chart {
+            continue;
+        }
+        let mut bytes = Vec::new();
+        entry.read_to_end(&mut bytes).map_err(invalid)?;
+        let json = decode_json(bytes, &path)?;
+
+        if path == Path::new("manifest.json") {
+            manifest = Some(serde_json::from_str::<Manifest>(&json).map_err(parse_error(&path))?);
+        } else if path == Path::new("content.json") {
+            let validated: ValidatedSoundMap =
+                serde_json::from_str(&json).map_err(parse_error(&path))?;
+            let mut validated = validated.into_inner();
+            validated.ensure_tempo();
+            soundmap = Some(validated);
+        } else {
+            let chart: Chart = serde_json::from_str(&json).map_err(parse_error(&path))?;
+            charts.push((path, chart));
+        }
+    }
+
+    let manifest =
+        manifest.ok_or_else(|| SmapError::InvalidArchive("Cannot find manifest.json".into()))?;
+    let soundmap =
+        soundmap.ok_or_else(|| SmapError::InvalidArchive("Cannot find content.json".into()))?;
+    charts.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok((
+        manifest,
+        soundmap,
+        charts.into_iter().map(|(_, chart)| chart).collect(),
+    ))
+}
+
+/// Read only the manifest from a `*.smap` file. (ex. for listing pages)
+///
+/// The archive is read until `manifest.json`, and other entries are not read or extracted.
+pub fn read_manifest_from_smap(smap_file_path: &str) -> Result<Manifest, SmapError> {
+    read_smap_entry(smap_file_path, |path| path == Path::new("manifest.json"))?
+        .ok_or_else(|| SmapError::InvalidArchive("Cannot find manifest.json".into()))
+}
+
+/// Read only a chart which named `chart_name` from a `*.smap` file.
+///
+/// The archive is read until `charts/{chart_name}.json` (or in a subdirectory of a chart type),
+/// so other charts are not parsed.
+pub fn read_chart_from_smap(smap_file_path: &str, chart_name: &str) -> Result<Chart, SmapError> {
+    let file_name = format!("{}.json", sanitize_file_name(chart_name));
+    read_smap_entry(smap_file_path, |path| {
+        path.starts_with("charts") && path.file_name().is_some_and(|name| *name == *file_name)
+    })?
+    .ok_or_else(|| SmapError::ChartNotFound(chart_name.to_string()))
+}
+
+/// Parse the first JSON file of a `*.smap` file which `is_target`, and stop reading.
+fn read_smap_entry<T: DeserializeOwned>(
+    smap_file_path: &str,
+    is_target: impl Fn(&Path) -> bool,
+) -> Result<Option<T>, SmapError> {
+    let invalid = |e: io::Error| SmapError::InvalidArchive(e.to_string());
+
+    let decoder = Decoder::new(File::open(smap_file_path)?).map_err(invalid)?;
+    let mut archive = tar::Archive::new(decoder);
+    for entry in archive.entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+        let path = entry.path().map_err(invalid)?.to_path_buf();
+        if !entry.header().entry_type().is_file() || !is_target(&path) {
+            continue;
+        }
+
+        let mut bytes = Vec::new();
+        entry.read_to_end(&mut bytes).map_err(invalid)?;
+        let json = decode_json(bytes, &path)?;
+        return serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|source| SmapError::Json { file: path, source });
+    }
+
+    Ok(None)
+}
+
+/// A default limit of `load_smap_url`. (256 MiB)
+#[cfg(feature = "reqwest")]
+pub const DEFAULT_DOWNLOAD_LIMIT: u64 = 256 * 1024 * 1024;
+
+/// Download a `*.smap` file and load it in memory. Nothing is written to disk.
+///
+/// It fails if the response is not `200 OK`, or larger than `DEFAULT_DOWNLOAD_LIMIT`.
+#[cfg(feature = "reqwest")]
+pub fn load_smap_url(url: &str) -> Result<(Manifest, SoundMap, Vec<Chart>), SmapError> {
+    load_smap_url_with_limit(url, DEFAULT_DOWNLOAD_LIMIT)
+}
+
+/// Same as `load_smap_url`, but with a limit of the downloaded size in bytes.
+#[cfg(feature = "reqwest")]
+pub fn load_smap_url_with_limit(
+    url: &str,
+    max_bytes: u64,
+) -> Result<(Manifest, SoundMap, Vec<Chart>), SmapError> {
+    let download_error = |e: reqwest::Error| SmapError::Download(e.to_string());
+    let too_large = || SmapError::Download(format!("{url} is larger than {max_bytes} bytes"));
+
+    let response = reqwest::blocking::get(url).map_err(download_error)?;
+    if response.status() != reqwest::StatusCode::OK {
+        return Err(SmapError::Download(format!(
+            "{url} responded with {}",
+            response.status()
+        )));
+    }
+    if response.content_length().is_some_and(|len| len > max_bytes) {
+        return Err(too_large());
+    }
+
+    // Content-Length can be missing or wrong, so check the actual size too
+    let mut smap = Vec::new();
+    response.take(max_bytes + 1).read_to_end(&mut smap)?;
+    if smap.len() as u64 > max_bytes {
+        return Err(too_large());
+    }
+
+    load_smap_bytes(&smap)
+}
+
+/// Iterate charts of a soundmap directory. Each chart file is read and parsed lazily on
+/// `next()`, so only one chart is in memory at once.
+///
+/// If the charts directory can't be read, it yields only the error.
+pub fn iter_charts(smap_path: &str) -> impl Iterator<Item = Result<Chart, SmapError>> {
+    let (paths, error) = match chart_files(&Path::new(smap_path).join("charts")) {
+        Ok(paths) => (paths, None),
+        Err(e) => (Vec::new(), Some(e)),
+    };
+
+    error
+        .map(Err)
+        .into_iter()
+        .chain(paths.into_iter().map(|path| read_json(&path)))
+}
+
+/// Find chart files (`*.json`) in the charts directory, including its subdirectories.
+///
+/// Other files (ex. `.DS_Store`) are skipped. (See `other_chart_dir_files`)
+fn chart_files(charts_dir: &Path) -> Result<Vec<PathBuf>, SmapError> {
+    Ok(files_in(charts_dir)?
+        .into_iter()
+        .filter(|path| is_chart_file(path))
+        .collect())
+}
+
+/// Find files in the charts directory which are not chart files.
+fn other_chart_dir_files(charts_dir: &Path) -> Result<Vec<PathBuf>, SmapError> {
+    Ok(files_in(charts_dir)?
+        .into_iter()
+        .filter(|path| !is_chart_file(path))
+        .collect())
+}
+
+fn is_chart_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+/// Find files in the directory, including its subdirectories.
+fn files_in(dir: &Path) -> Result<Vec<PathBuf>, SmapError> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.append(&mut files_in(&path)?);
+        } else if path.is_file() {
+            files.push(path);
         }
     }
+    Ok(files)
+}
+
+/// Options for saving soundmap format files.
+#[derive(Debug, Clone, Default)]
+pub struct SaveOptions {
+    /// Save charts into subdirectories named by the chart type. (ex. `charts/4K/Hard.json`)
+    pub group_charts_by_type: bool,
+
+    /// Remove the soundmap directory first, if it already exists.
+    pub overwrite: bool,
+}
+
+impl SaveOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn group_charts_by_type(mut self) -> Self {
+        self.group_charts_by_type = true;
+        self
+    }
 
-    Ok((manifest, soundmap, charts))
+    pub fn overwrite(mut self) -> Self {
+        self.overwrite = true;
+        self
+    }
 }
 
 /// Generate soundmap format files.
+///
+/// If `generator` of the manifest isn't set, it is stamped with this library. (See
+/// `types::manifest::GENERATOR`)
 pub fn save_smap_dir(
     smap_name: &str,
     save_path: &str,
     manifest: &Manifest,
     soundmap: &SoundMap,
-    charts: &Vec<Chart>,
-) -> io::Result<()> {
+    charts: &[Chart],
+) -> Result<(), SmapError> {
+    save_smap_dir_with_options(
+        smap_name,
+        save_path,
+        manifest,
+        soundmap,
+        charts,
+        &SaveOptions::default(),
+    )
+}
+
+/// Generate soundmap format files, and replace the soundmap directory if it already exists.
+pub fn save_smap_dir_overwrite(
+    smap_name: &str,
+    save_path: &str,
+    manifest: &Manifest,
+    soundmap: &SoundMap,
+    charts: &[Chart],
+) -> Result<(), SmapError> {
+    save_smap_dir_with_options(
+        smap_name,
+        save_path,
+        manifest,
+        soundmap,
+        charts,
+        &SaveOptions::new().overwrite(),
+    )
+}
+
+/// Generate soundmap format files with options.
+///
+/// Parent directories of `save_path` are created if they don't exist. It returns
+/// `SmapError::AlreadyExists` if `{save_path}/{smap_name}` exists, unless `overwrite` is set.
+pub fn save_smap_dir_with_options(
+    smap_name: &str,
+    save_path: &str,
+    manifest: &Manifest,
+    soundmap: &SoundMap,
+    charts: &[Chart],
+    options: &SaveOptions,
+) -> Result<(), SmapError> {
     // Make a soundmap format directory
     let format_path = format!("{save_path}/{smap_name}");
     let charts_dir = format!("{format_path}/charts");
-    fs::create_dir(&format_path)?;
-    fs::create_dir(&charts_dir)?;
+
+    // Find chart files which have same name, before writing anything
+    let mut chart_paths: Vec<(PathBuf, &Chart)> = Vec::new();
+    for chart in charts {
+        let mut chart_path = PathBuf::from(&charts_dir);
+        if options.group_charts_by_type {
+            chart_path.push(sanitize_file_name(chart.canonical_type()));
+        }
+        chart_path.push(chart_file_name(chart));
+
+        if chart_paths.iter().any(|(path, _)| *path == chart_path) {
+            return Err(SmapError::FileCollision(chart_path));
+        }
+        chart_paths.push((chart_path, chart));
+    }
+
+    if Path::new(&format_path).exists() {
+        if !options.overwrite {
+            return Err(SmapError::AlreadyExists(PathBuf::from(format_path)));
+        }
+        fs::remove_dir_all(&format_path)?;
+    }
+    fs::create_dir_all(&charts_dir)?;
 
     // Also, create sound directory
     let sound_path = format!("{format_path}/sounds");
-    fs::create_dir(sound_path)?;
+    fs::create_dir_all(sound_path)?;
 
     // Save manifest
     let manifest_path = format!("{format_path}/manifest.json");
-    fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?)?;
+    fs::write(
+        &manifest_path,
+        to_json(&stamped(manifest), Path::new(&manifest_path))?,
+    )?;
 
     // Save soundmap
     let soundmap_path = format!("{format_path}/content.json");
-    fs::write(&soundmap_path, serde_json::to_string_pretty(&soundmap)?)?;
+    fs::write(
+        &soundmap_path,
+        to_json(soundmap, Path::new(&soundmap_path))?,
+    )?;
 
     // Save charts
-    for chart in charts {
-        let chart_path = format!("{charts_dir}/{}.json", chart.name);
-        fs::write(&chart_path, serde_json::to_string_pretty(&chart)?)?;
+    for (chart_path, _) in &chart_paths {
+        fs::create_dir_all(chart_path.parent().unwrap())?;
     }
+    write_charts(&chart_paths)
+}
 
+#[cfg(not(feature = "rayon"))]
+fn write_charts(chart_paths: &[(PathBuf, &Chart)]) -> Result<(), SmapError> {
+    for (chart_path, chart) in chart_paths {
+        fs::write(chart_path, to_json(chart, chart_path)?)?;
+    }
     Ok(())
 }
 
-/// Check soundmap directory
-pub fn check_smap(smap_path: &str) -> Result<(), String> {
-    // Set directory path
-    let manifest_path = format!("{smap_path}/manifest.json");
-    let soundmap_path = format!("{smap_path}/content.json");
-    let charts_dir_path = format!("{smap_path}/charts");
-
-    // Check manifest if valid
-    match fs::read_to_string(&manifest_path) {
-        Ok(m) => match serde_json::from_str::<Manifest>(&m) {
-            Ok(_manifest) => {}
-            Err(e) => return Err(format!("Failed to parse manifest: {}", e)),
-        },
-        Err(e) => return Err(format!("Failed to read manifest: {}", e)),
-    }
-
-    // Check soundmap if valid
-    match fs::read_to_string(&soundmap_path) {
-        Ok(s) => match serde_json::from_str::<SoundMap>(&s) {
-            Ok(_soundmap) => {}
-            Err(e) => return Err(format!("Failed to parse soundmap: {}", e)),
-        },
-        Err(e) => return Err(format!("Failed to read soundmap: {}", e)),
-    }
-
-    // Check charts if valid
-    if Path::new(&charts_dir_path).exists() {
-        for entry in fs::read_dir(&charts_dir_path).unwrap() {
-            let entry = entry.unwrap();
-            let path = entry.path();
-            if path.is_file() {
-                match fs::read_to_string(&path.to_str().unwrap()) {
-                    Ok(c) => match serde_json::from_str::<Chart>(&c) {
-                        Ok(_chart) => {}
-                        Err(e) => return Err(format!("Failed to parse chart: {}", e)),
-                    },
-                    Err(e) => return Err(format!("Failed to read chart: {}", e)),
-                }
-            }
-        }
-    } else {
-        return Err("Cannot find charts directory".to_string());
+/// Serialize and write charts in parallel.
+#[cfg(feature = "rayon")]
+fn write_charts(chart_paths: &[(PathBuf, &Chart)]) -> Result<(), SmapError> {
+    use rayon::prelude::*;
+
+    chart_paths.par_iter().try_for_each(|(chart_path, chart)| {
+        fs::write(chart_path, to_json(chart, chart_path)?)?;
+        Ok(())
+    })
+}
+
+/// Get the manifest with `generator`, if it isn't set.
+fn stamped(manifest: &Manifest) -> Manifest {
+    let mut manifest = manifest.clone();
+    manifest
+        .generator
+        .get_or_insert_with(|| types::manifest::GENERATOR.to_string());
+    manifest
+}
+
+/// Get a file name of the chart. (`{name}.json`)
+fn chart_file_name(chart: &Chart) -> String {
+    format!("{}.json", sanitize_file_name(&chart.name))
+}
+
+/// Find chart files which don't match their `name` field, without renaming. (See
+/// `resync_chart_filenames`)
+///
+/// It returns `(current path, expected path)` of each file. They are also returned by
+/// `load_smap_dir_with_report`, and warnings of `inspect_smap` and `ingest_smap_dir`.
+pub fn check_chart_filenames(smap_path: &str) -> Result<Vec<(PathBuf, PathBuf)>, SmapError> {
+    let mut mismatches = Vec::new();
+    for path in chart_files(&Path::new(smap_path).join("charts"))? {
+        let chart: Chart = read_json(&path)?;
+        mismatches.extend(chart_filename_mismatch(&path, &chart));
     }
+    Ok(mismatches)
+}
 
-    Ok(())
+/// Get `(current path, expected path)` if the chart file doesn't match its `name` field.
+fn chart_filename_mismatch(path: &Path, chart: &Chart) -> Option<(PathBuf, PathBuf)> {
+    let expected_path = path.with_file_name(chart_file_name(chart));
+    (expected_path != path).then(|| (path.to_path_buf(), expected_path))
 }
 
-/// Pack to `*.smap`(or starts with something) file. It uses tar with lz4 compression.
-pub fn pack(target_path: &str, smap_dir_name: &str, filename: &str) -> io::Result<()> {
-    let smap_filename = format!("{target_path}/{filename}");
-    let smap_dir_path = format!("{target_path}/{smap_dir_name}");
-    let temp_tar_name = format!("{target_path}/_temp.tar");
+/// Rename chart files to match their `name` field. (See `chart_file_name`)
+///
+/// It returns renames which performed. If a rename would overwrite another file, nothing is
+/// renamed.
+pub fn resync_chart_filenames(smap_path: &str) -> Result<Vec<(PathBuf, PathBuf)>, SmapError> {
+    let charts_dir = format!("{smap_path}/charts");
+    let files = chart_files(Path::new(&charts_dir))?;
 
-    // Make temp tar.
-    let mut tar_file = File::create(&temp_tar_name)?;
-    let mut temp_tar = tar::Builder::new(&mut tar_file);
+    let mut renames = Vec::new();
+    let mut final_paths = Vec::new();
+    for path in &files {
+        let chart: Chart = read_json(path)?;
 
-    let target_charts_path = format!("{smap_dir_path}/charts");
-    let target_sounds_path = format!("{smap_dir_path}/sounds");
+        let new_path = path.with_file_name(chart_file_name(&chart));
+        if new_path != *path {
+            // Don't overwrite a file which is not a chart
+            if new_path.exists() && !files.contains(&new_path) {
+                return Err(SmapError::FileCollision(new_path));
+            }
+            renames.push((path.clone(), new_path.clone()));
+        }
 
-    temp_tar.append_file(
-        "manifest.json",
-        &mut File::open(format!("{smap_dir_path}/manifest.json")).unwrap(),
-    )?;
-    temp_tar.append_file(
-        "content.json",
-        &mut File::open(format!("{smap_dir_path}/content.json")).unwrap(),
-    )?;
+        if final_paths.contains(&new_path) {
+            return Err(SmapError::FileCollision(new_path));
+        }
+        final_paths.push(new_path);
+    }
 
-    temp_tar.append_dir(format!("charts"), ".")?;
-    temp_tar.append_dir(format!("sounds"), ".")?;
+    // Move to temporary names first, for swapped names
+    for (from, _to) in &renames {
+        fs::rename(from, from.with_extension("json.resync"))?;
+    }
+    for (from, to) in &renames {
+        fs::rename(from.with_extension("json.resync"), to)?;
+    }
 
-    let charts_ls = fs::read_dir(target_charts_path).unwrap();
+    Ok(renames)
+}
 
-    for dir_entry in charts_ls {
-        let path = dir_entry.unwrap().path();
-        let chart_path_str = path.to_str().unwrap();
-        let chart_name = &chart_path_str.split('/').last().unwrap();
-        let chart_tar_path = format!("charts/{chart_name}");
-        temp_tar.append_file(chart_tar_path, &mut File::open(chart_path_str).unwrap())?;
+/// Replace characters which can't be used in a file name.
+///
+/// An empty name, `.` and `..` are replaced with `_`, so a file can't be out of its directory.
+fn sanitize_file_name(name: &str) -> String {
+    if matches!(name, "" | "." | "..") {
+        return "_".to_string();
     }
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c => c,
+        })
+        .collect()
+}
 
-    let sounds_ls = fs::read_dir(target_sounds_path).unwrap();
+/// Get a hash of soundmap contents.
+///
+/// The hash is stable across builds and doesn't depend on the order of charts.
+pub fn content_hash(manifest: &Manifest, soundmap: &SoundMap, charts: &[Chart]) -> u64 {
+    let mut charts: Vec<&Chart> = charts.iter().collect();
+    charts.sort_by(|a, b| a.name.cmp(&b.name));
 
-    for dir_entry in sounds_ls {
-        let path = dir_entry.unwrap().path();
-        let sound_path_str = path.to_str().unwrap();
-        let sound_name = &sound_path_str.split('/').last().unwrap();
-        let sound_tar_path = format!("sounds/{sound_name}");
-        temp_tar.append_file(sound_tar_path, &mut File::open(sound_path_str).unwrap())?;
+    let mut hasher = hash::Fnv1a::default();
+    // Serializing these types can't fail
+    hasher.write(&serde_json::to_vec(manifest).unwrap());
+    hasher.write(&serde_json::to_vec(soundmap).unwrap());
+    for chart in charts {
+        hasher.write(&serde_json::to_vec(chart).unwrap());
     }
+    hasher.finish()
+}
 
-    temp_tar.finish()?;
+/// A result of `ingest_smap_dir`.
+#[derive(Debug, Clone)]
+pub struct IngestResult {
+    pub manifest: Manifest,
+    pub soundmap: SoundMap,
+    pub charts: Vec<Chart>,
 
-    // Comression with LZ4
-    let mut input_file = File::open(&temp_tar_name)?;
-    let output_file = File::create(smap_filename)?;
-    let mut encoder = EncoderBuilder::new().level(4).build(output_file)?;
-    std::io::copy(&mut input_file, &mut encoder)?;
-    let (_output, result) = encoder.finish();
+    /// Warnings of `inspect_smap`
+    pub warnings: Vec<CheckIssue>,
 
-    fs::remove_file(&temp_tar_name)?;
-    fs::remove_dir_all(&smap_dir_path)?;
+    /// A hash of contents. (See `content_hash`)
+    pub hash: u64,
+}
+
+/// Check, load and hash a soundmap directory at once.
+///
+/// It fails on errors of `check_smap`, but warnings are collected in the result.
+pub fn ingest_smap_dir(smap_path: &str) -> Result<IngestResult, SmapError> {
+    let report = inspect_smap(smap_path);
+    if let Some(error) = report.errors.into_iter().next() {
+        return Err(SmapError::Check(error.to_string()));
+    }
+    let warnings = report.warnings;
+    let (manifest, soundmap, charts) = load_smap_dir(smap_path)?;
+    let hash = content_hash(&manifest, &soundmap, &charts);
 
-    result
+    Ok(IngestResult {
+        manifest,
+        soundmap,
+        charts,
+        warnings,
+        hash,
+    })
 }
 
-/// Pack to `*.smap`(or starts with something) file. It uses tar with lz4 compression.
-pub fn unpack(smap_file_path: &str, save_path: &str) -> io::Result<()> {
-    let temp_tar_name = format!("{save_path}/_temp.tar");
+/// A default lz4 compression level of `pack`.
+pub const DEFAULT_PACK_LEVEL: u32 = 4;
 
-    let input_file = File::open(smap_file_path)?;
-    let mut decoder = Decoder::new(input_file)?;
-    let mut temp_tar = File::create(&temp_tar_name)?;
-    io::copy(&mut decoder, &mut temp_tar)?;
+/// A range of lz4 compression levels.
+///
+/// `0` ~ `2` use the fast compressor, and `3` ~ `12` use the high compression one. Higher
+/// levels are smaller but slower. Levels above `12` are same as `12` in lz4.
+pub const PACK_LEVELS: RangeInclusive<u32> = 0..=16;
 
-    let temp_tar_file = File::open(&temp_tar_name)?;
-    let mut temp_tar = tar::Archive::new(temp_tar_file);
-    temp_tar.unpack(save_path)?;
+/// Options for packing `*.smap` files.
+#[derive(Debug, Clone)]
+pub struct PackOptions {
+    /// Use a fixed timestamp and normalize modes and ownership in tar headers, so same content
+    /// is always packed to same bytes. (See `tar::HeaderMode::Deterministic`)
+    pub deterministic: bool,
 
-    fs::remove_file(&temp_tar_name)?;
+    /// A lz4 compression level. (See `PACK_LEVELS`)
+    pub level: u32,
+}
 
-    Ok(())
+impl Default for PackOptions {
+    fn default() -> Self {
+        Self {
+            deterministic: false,
+            level: DEFAULT_PACK_LEVEL,
+        }
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::path::Path;
+impl PackOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    use super::*;
+    pub fn deterministic(mut self) -> Self {
+        self.deterministic = true;
+        self
+    }
 
-    #[test]
-    #[ignore = "not ready for new format"]
-    fn load_smap() {
-        let (manifest, soundmap, charts) = load_smap_dir("test_files/example").unwrap();
+    pub fn with_level(mut self, level: u32) -> Self {
+        self.level = level;
+        self
+    }
+}
 
-        // Check manifest
-        assert_eq!(manifest.title, "Example");
-        assert_eq!(manifest.artists[0], "Example");
-        assert_eq!(manifest.get_sound_path(0).unwrap(), "kick.wav");
+/// Pack to `*.smap`(or starts with something) file. It uses tar with lz4 compression.
+///
+/// The soundmap directory is kept. (See `pack_and_consume`)
+pub fn pack(target_path: &str, smap_dir_name: &str, filename: &str) -> Result<(), SmapError> {
+    pack_with_options(
+        target_path,
+        smap_dir_name,
+        filename,
+        &PackOptions::default(),
+    )
+}
 
-        // Check soundmap
-        assert_eq!(soundmap.audio_format, "wav");
-        assert_eq!(soundmap.audio_bits, 24);
-        assert_eq!(soundmap.audio_sample_rate, 48000);
-        assert_eq!(soundmap.notes.len(), 4);
-        assert_eq!(soundmap.notes[0].time, 1056);
-        assert_eq!(soundmap.notes[1].time, 1320);
+/// Pack to `*.smap`(or starts with something) file with the lz4 compression level.
+///
+/// It fails if `level` is not in `PACK_LEVELS`.
+pub fn pack_with_level(
+    target_path: &str,
+    smap_dir_name: &str,
+    filename: &str,
+    level: u32,
+) -> Result<(), SmapError> {
+    pack_with_options(
+        target_path,
+        smap_dir_name,
+        filename,
+        &PackOptions::new().with_level(level),
+    )
+}
 
-        // Check charts
-        assert_eq!(charts.len(), 1);
+/// Pack to `*.smap`(or starts with something) file with options.
+pub fn pack_with_options(
+    target_path: &str,
+    smap_dir_name: &str,
+    filename: &str,
+    options: &PackOptions,
+) -> Result<(), SmapError> {
+    if !PACK_LEVELS.contains(&options.level) {
+        return Err(SmapError::InvalidLevel(options.level));
     }
 
-    #[test]
-    fn save_smap() {
-        let dir_name = "test_files/test";
+    let target_path = Path::new(target_path);
+    let output_file = File::create(target_path.join(filename))?;
+    pack_dir_to_writer(&target_path.join(smap_dir_name), output_file, options)?;
 
-        // If the dir exists, delete it
-        if Path::new(dir_name).exists() {
-            fs::remove_dir_all(dir_name).unwrap();
-        }
+    Ok(())
+}
 
-        let new_manifest = Manifest::new("Test", "Various Artists");
-        let new_soundmap = SoundMap::new();
-        let new_charts: Vec<Chart> = Vec::new();
+/// Same as `pack`, but the soundmap directory is removed after packing.
+pub fn pack_and_consume(
+    target_path: &str,
+    smap_dir_name: &str,
+    filename: &str,
+) -> Result<(), SmapError> {
+    pack(target_path, smap_dir_name, filename)?;
+    fs::remove_dir_all(Path::new(target_path).join(smap_dir_name))?;
 
-        save_smap_dir(
-            "test",
-            "test_files",
-            &new_manifest,
-            &new_soundmap,
-            &new_charts,
-        )
-        .unwrap();
+    Ok(())
+}
 
-        // Recheck which saved
-        let (saved_manifest, saved_soundmap, saved_charts) = load_smap_dir(dir_name).unwrap();
+/// Pack a soundmap directory to `writer`, without writing any files. (ex. for serving
+/// downloads)
+///
+/// The source directory is not removed. It returns `writer` after the lz4 stream is finished.
+pub fn pack_to_writer<W: Write>(
+    smap_dir_path: &str,
+    writer: W,
+    options: &PackOptions,
+) -> Result<W, SmapError> {
+    pack_dir_to_writer(Path::new(smap_dir_path), writer, options)
+}
 
-        // Check manifest
-        assert_eq!(saved_manifest.title, "Test");
-        assert_eq!(saved_manifest.artists[0], "Various Artists");
+/// Pack a soundmap directory to bytes of `*.smap` file. (See `pack_to_writer`)
+pub fn pack_to_bytes(smap_dir_path: &str, options: &PackOptions) -> Result<Vec<u8>, SmapError> {
+    pack_to_writer(smap_dir_path, Vec::new(), options)
+}
 
-        // Check soundmap
-        assert_eq!(saved_soundmap.audio_format, "wav");
-        assert_eq!(saved_soundmap.audio_bits, 24);
-        assert_eq!(saved_soundmap.audio_sample_rate, 48000);
+fn pack_dir_to_writer<W: Write>(
+    smap_dir_path: &Path,
+    writer: W,
+    options: &PackOptions,
+) -> Result<W, SmapError> {
+    if !PACK_LEVELS.contains(&options.level) {
+        return Err(SmapError::InvalidLevel(options.level));
+    }
 
-        // Check charts
-        assert_eq!(saved_charts.len(), 0);
+    // Make tar in memory
+    let mut temp_tar = tar::Builder::new(Cursor::new(Vec::new()));
+    if options.deterministic {
+        temp_tar.mode(tar::HeaderMode::Deterministic);
     }
 
-    #[test]
-    #[ignore = "not ready for new format"]
-    fn check_smap_valid() {
-        match check_smap("test_files/example") {
-            Ok(_) => (),
-            Err(e) => panic!("{}", e),
+    for (entry_name, source) in pack_entries(smap_dir_path)? {
+        match source {
+            Some(path) => temp_tar.append_file(entry_name, &mut File::open(path)?)?,
+            None => temp_tar.append_dir(entry_name, ".")?,
         }
     }
 
-    // Pack and unpack soundmap test
-    #[test]
-    fn pack_smap() {
-        let test_dir = "test_files";
-        let smap_name = "pack_test";
-        // let smap_path = format!("{test_dir}/{smap_name}");
-        let filename = format!("{smap_name}.smap");
-        let smap_file_path = format!("{test_dir}/{filename}");
-        let smap_result_path = format!("{test_dir}/unpack_result");
+    let temp_tar = temp_tar.into_inner()?.into_inner();
 
-        // If the file and directory exists, delete it
-        if Path::new(&smap_file_path).exists() {
-            fs::remove_file(&smap_file_path).unwrap();
-        }
-        if Path::new(&smap_result_path).exists() {
-            fs::remove_dir_all(&smap_result_path).unwrap();
-        }
+    // Comression with LZ4
+    let mut encoder = EncoderBuilder::new().level(options.level).build(writer)?;
+    encoder.write_all(&temp_tar)?;
+    let (writer, result) = encoder.finish();
+    result?;
 
-        let new_manifest = Manifest::new("Test", "Various Artists");
-        let new_soundmap = SoundMap::new();
-        let new_charts: Vec<Chart> = Vec::new();
+    Ok(writer)
+}
 
-        save_smap_dir(
-            smap_name,
-            test_dir,
-            &new_manifest,
-            &new_soundmap,
-            &new_charts,
-        )
-        .unwrap();
+/// List tar entry names which `pack` will archive, in the order of archiving.
+pub fn list_pack_contents(smap_dir_path: &str) -> Result<Vec<String>, SmapError> {
+    Ok(pack_entries(Path::new(smap_dir_path))?
+        .into_iter()
+        .map(|(entry_name, _source)| entry_name)
+        .collect())
+}
 
-        pack(test_dir, smap_name, &filename).unwrap();
+/// Get tar entry names and their source files. Directories have no source file.
+///
+/// Entry names always use `/`, even if the paths of the platform don't.
+fn pack_entries(smap_dir_path: &Path) -> Result<Vec<(String, Option<PathBuf>)>, SmapError> {
+    let mut entries = vec![
+        (
+            "manifest.json".to_string(),
+            Some(smap_dir_path.join("manifest.json")),
+        ),
+        (
+            "content.json".to_string(),
+            Some(smap_dir_path.join("content.json")),
+        ),
+        ("charts".to_string(), None),
+        ("sounds".to_string(), None),
+    ];
 
-        // Unpack `*.smap` file
-        fs::create_dir(&smap_result_path).unwrap();
-        unpack(&smap_file_path, &smap_result_path).unwrap();
+    let charts_dir = smap_dir_path.join("charts");
+    let mut chart_entries = Vec::new();
+    for path in chart_files(&charts_dir)? {
+        // Keep subdirectories of charts
+        let chart_name = path
+            .strip_prefix(&charts_dir)
+            .unwrap()
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        chart_entries.push((format!("charts/{chart_name}"), Some(path)));
+    }
 
-        // Recheck which saved
+    let mut sound_entries = Vec::new();
+    for dir_entry in fs::read_dir(smap_dir_path.join("sounds"))? {
+        let dir_entry = dir_entry?;
+        let path = dir_entry.path();
+        if path.is_file() {
+            let sound_name = dir_entry.file_name().to_string_lossy().to_string();
+            sound_entries.push((format!("sounds/{sound_name}"), Some(path)));
+        }
+    }
+
+    // Same order for every pack
+    chart_entries.sort();
+    sound_entries.sort();
+    entries.append(&mut chart_entries);
+    entries.append(&mut sound_entries);
+
+    Ok(entries)
+}
+
+/// Pack to `*.smap`(or starts with something) file directly from loaded data, without saving
+/// a soundmap directory.
+///
+/// `sounds` is a map of sound id and its file data. The file name is given by the manifest.
+pub fn pack_from_memory(
+    filename: &str,
+    manifest: &Manifest,
+    soundmap: &SoundMap,
+    charts: &[Chart],
+    sounds: &HashMap<u16, Vec<u8>>,
+) -> Result<(), SmapError> {
+    let mut temp_tar = tar::Builder::new(Vec::new());
+
+    append_tar_data(
+        &mut temp_tar,
+        "manifest.json",
+        to_json(&stamped(manifest), Path::new("manifest.json"))?.as_bytes(),
+    )?;
+    append_tar_data(
+        &mut temp_tar,
+        "content.json",
+        to_json(soundmap, Path::new("content.json"))?.as_bytes(),
+    )?;
+
+    append_tar_dir(&mut temp_tar, "charts")?;
+    append_tar_dir(&mut temp_tar, "sounds")?;
+
+    for chart in charts {
+        let entry_name = format!("charts/{}", chart_file_name(chart));
+        let chart = to_json(chart, Path::new(&entry_name))?;
+        append_tar_data(&mut temp_tar, &entry_name, chart.as_bytes())?;
+    }
+
+    // Keep order of sounds same in every pack
+    let mut sound_ids: Vec<&u16> = sounds.keys().collect();
+    sound_ids.sort();
+
+    for id in sound_ids {
+        let sound_name = manifest.get_sound_path(*id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Sound {id} is not in the manifest"),
+            )
+        })?;
+        append_tar_data(&mut temp_tar, &format!("sounds/{sound_name}"), &sounds[id])?;
+    }
+
+    let temp_tar = temp_tar.into_inner()?;
+
+    // Comression with LZ4
+    let output_file = File::create(filename)?;
+    let mut encoder = EncoderBuilder::new()
+        .level(DEFAULT_PACK_LEVEL)
+        .build(output_file)?;
+    encoder.write_all(&temp_tar)?;
+    let (_output, result) = encoder.finish();
+
+    Ok(result?)
+}
+
+fn append_tar_data<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &str,
+    data: &[u8],
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, path, data)
+}
+
+fn append_tar_dir<W: Write>(builder: &mut tar::Builder<W>, path: &str) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_size(0);
+    header.set_mode(0o755);
+    builder.append_data(&mut header, path, io::empty())
+}
+
+/// Pack to `*.smap`(or starts with something) file. It uses tar with lz4 compression.
+///
+/// If the file doesn't start with the lz4 magic number, it is unpacked as a plain tar.
+/// (See `unpack_tar`)
+pub fn unpack(smap_file_path: &str, save_path: &str) -> Result<(), SmapError> {
+    unpack_from_reader(File::open(smap_file_path)?, save_path)
+}
+
+/// Unpack `*.smap` data from `reader` to `save_path`, without a temporary tar file.
+/// (ex. for validating uploads)
+///
+/// Same as `unpack`, data which doesn't start with the lz4 magic number is unpacked as a plain
+/// tar.
+pub fn unpack_from_reader<R: Read>(reader: R, save_path: &str) -> Result<(), SmapError> {
+    let mut reader = BufReader::new(reader);
+    if reader.fill_buf()?.starts_with(&LZ4_MAGIC) {
+        tar::Archive::new(Decoder::new(reader)?).unpack(save_path)?;
+    } else {
+        tar::Archive::new(reader).unpack(save_path)?;
+    }
+    Ok(())
+}
+
+/// Unpack bytes of `*.smap` file to `save_path`. (See `unpack_from_reader`)
+pub fn unpack_from_bytes(smap: &[u8], save_path: &str) -> Result<(), SmapError> {
+    unpack_from_reader(smap, save_path)
+}
+
+/// Limits of `unpack_with_limits`, against malicious archives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnpackLimits {
+    /// A limit of total decompressed size of files in bytes
+    pub max_bytes: u64,
+
+    /// A limit of entry count, including directories
+    pub max_entries: usize,
+}
+
+impl Default for UnpackLimits {
+    fn default() -> Self {
+        Self {
+            // 1 GiB
+            max_bytes: 1024 * 1024 * 1024,
+            max_entries: 65536,
+        }
+    }
+}
+
+impl UnpackLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+}
+
+/// Same as `unpack`, but it aborts when the archive exceeds `limits`.
+///
+/// Entries are unpacked one by one without a temporary tar, so files which are unpacked before
+/// the abort are left in `save_path`.
+pub fn unpack_with_limits(
+    smap_file_path: &str,
+    save_path: &str,
+    limits: &UnpackLimits,
+) -> Result<(), SmapError> {
+    let file = File::open(smap_file_path)?;
+    let reader: Box<dyn Read> = if is_lz4_file(smap_file_path)? {
+        Box::new(Decoder::new(file)?)
+    } else {
+        Box::new(file)
+    };
+    let mut archive = tar::Archive::new(reader);
+
+    let mut total_bytes: u64 = 0;
+    for (index, entry) in archive.entries()?.enumerate() {
+        if index >= limits.max_entries {
+            return Err(SmapError::TooManyEntries(limits.max_entries));
+        }
+
+        let mut entry = entry?;
+        total_bytes = total_bytes.saturating_add(entry.size());
+        if total_bytes > limits.max_bytes {
+            return Err(SmapError::TooLarge(limits.max_bytes));
+        }
+        entry.unpack_in(save_path)?;
+    }
+
+    Ok(())
+}
+
+/// The magic number of lz4 frame
+const LZ4_MAGIC: [u8; 4] = [0x04, 0x22, 0x4D, 0x18];
+
+fn is_lz4_file(path: &str) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == LZ4_MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Unpack a plain tar file without lz4 compression. (ex. an intermediate file of a pipeline)
+pub fn unpack_tar(tar_file_path: &str, save_path: &str) -> Result<(), SmapError> {
+    let mut archive = tar::Archive::new(File::open(tar_file_path)?);
+    Ok(archive.unpack(save_path)?)
+}
+
+/// Unpack a plain tar file to `save_path`, and load it. (See `unpack_tar`)
+pub fn load_smap_tar(
+    tar_file_path: &str,
+    save_path: &str,
+) -> Result<(Manifest, SoundMap, Vec<Chart>), SmapError> {
+    unpack_tar(tar_file_path, save_path)?;
+    load_smap_dir(save_path)
+}
+
+/// Same as `unpack`, and check every sound of the manifest is in `sounds/`.
+///
+/// It returns paths of missing sounds, in the order of the manifest.
+pub fn unpack_verify_manifest(
+    smap_file_path: &str,
+    save_path: &str,
+) -> Result<Vec<String>, SmapError> {
+    unpack(smap_file_path, save_path)?;
+
+    let manifest: Manifest = read_json(&Path::new(save_path).join("manifest.json"))?;
+    let sounds_dir = Path::new(save_path).join("sounds");
+
+    Ok(manifest
+        .sounds
+        .into_iter()
+        .map(|s| s.path)
+        .filter(|path| !sounds_dir.join(path).is_file())
+        .collect())
+}
+
+/// Check a `*.smap` file is not truncated or corrupted, without writing any files.
+///
+/// The whole lz4 stream is decompressed, and tar entries are walked. It also checks
+/// `manifest.json` and `content.json` are in the archive.
+pub fn verify_smap_archive(smap_file_path: &str) -> Result<(), SmapError> {
+    let invalid = |e: io::Error| SmapError::InvalidArchive(e.to_string());
+
+    let decoder = Decoder::new(File::open(smap_file_path)?).map_err(invalid)?;
+    let mut archive = tar::Archive::new(decoder);
+
+    let mut has_manifest = false;
+    let mut has_soundmap = false;
+    for entry in archive.entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+        let path = entry.path().map_err(invalid)?.to_path_buf();
+        has_manifest |= path == Path::new("manifest.json");
+        has_soundmap |= path == Path::new("content.json");
+        io::copy(&mut entry, &mut io::sink()).map_err(invalid)?;
+    }
+
+    // Rest of the stream, after the end of tar
+    io::copy(&mut archive.into_inner(), &mut io::sink()).map_err(invalid)?;
+
+    if !has_manifest {
+        return Err(SmapError::InvalidArchive(
+            "Cannot find manifest.json".to_string(),
+        ));
+    }
+    if !has_soundmap {
+        return Err(SmapError::InvalidArchive(
+            "Cannot find content.json".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Get sound paths in the order of sound ids.
+pub fn sounds_in_manifest_order(manifest: &Manifest) -> Vec<&str> {
+    let mut sounds: Vec<_> = manifest.sounds.iter().collect();
+    sounds.sort_by_key(|s| s.id);
+    sounds.iter().map(|s| s.path.as_str()).collect()
+}
+
+/// Same as `unpack`, but sound files are written in the order of sound ids in the manifest,
+/// and the order is saved to `order.json`. Sound files which are not in the manifest are
+/// written after them.
+///
+/// It returns the recorded order.
+pub fn unpack_in_manifest_order(
+    smap_file_path: &str,
+    save_path: &str,
+) -> Result<Vec<String>, SmapError> {
+    let save_path = Path::new(save_path);
+    let temp_tar_name = save_path.join("_temp.tar");
+
+    let input_file = File::open(smap_file_path)?;
+    let mut decoder = Decoder::new(input_file)?;
+    let mut temp_tar = File::create(&temp_tar_name)?;
+    io::copy(&mut decoder, &mut temp_tar)?;
+
+    // Unpack except sounds, and remember where sounds are
+    let mut sound_entries = Vec::new();
+    let mut temp_tar = tar::Archive::new(File::open(&temp_tar_name)?);
+    for entry in temp_tar.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+        if !is_safe_entry_path(&path) {
+            return Err(SmapError::InvalidArchive(format!(
+                "Unsafe entry path: {}",
+                path.display()
+            )));
+        }
+        match path.strip_prefix("sounds") {
+            Ok(name) if entry.header().entry_type().is_file() => {
+                let name = name.to_string_lossy().to_string();
+                sound_entries.push((name, entry.raw_file_position(), entry.size()));
+            }
+            _ => {
+                entry.unpack_in(save_path)?;
+            }
+        }
+    }
+
+    let manifest: Manifest = read_json(&save_path.join("manifest.json"))?;
+    let mut order: Vec<String> = sounds_in_manifest_order(&manifest)
+        .into_iter()
+        .filter(|path| sound_entries.iter().any(|(name, _, _)| name == path))
+        .map(str::to_string)
+        .collect();
+    for (name, _, _) in &sound_entries {
+        if !order.contains(name) {
+            order.push(name.clone());
+        }
+    }
+
+    // Write sounds in the order
+    let sounds_dir = save_path.join("sounds");
+    fs::create_dir_all(&sounds_dir)?;
+    let mut temp_tar = File::open(&temp_tar_name)?;
+    for name in &order {
+        let (_, position, size) = sound_entries.iter().find(|(n, _, _)| n == name).unwrap();
+        temp_tar.seek(SeekFrom::Start(*position))?;
+        let sound_path = sounds_dir.join(name);
+        if let Some(parent) = sound_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut sound_file = File::create(sound_path)?;
+        io::copy(&mut (&mut temp_tar).take(*size), &mut sound_file)?;
+    }
+
+    let order_path = save_path.join("order.json");
+    fs::write(&order_path, to_json(&order, &order_path)?)?;
+    fs::remove_file(&temp_tar_name)?;
+
+    Ok(order)
+}
+
+/// Whether an entry path of an archive stays in the directory. (No `..`, root or prefix)
+fn is_safe_entry_path(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Export tempo and meter changes of the soundmap as a JSON file. (See `SoundMap::tempo_map`)
+///
+/// It is useful for rebuilding the grid in a DAW.
+pub fn export_tempo_map(soundmap: &SoundMap, path: &str) -> Result<(), SmapError> {
+    fs::write(path, to_json(&soundmap.tempo_map(), Path::new(path))?)?;
+    Ok(())
+}
+
+/// Same as `export_tempo_map`, but seconds are rounded to `precision` decimals, so the file is
+/// easy to diff.
+pub fn export_tempo_map_with_precision(
+    soundmap: &SoundMap,
+    path: &str,
+    precision: usize,
+) -> Result<(), SmapError> {
+    let scale = 10f64.powi(precision.min(15) as i32);
+    let mut tempo_map = soundmap.tempo_map();
+    for entry in &mut tempo_map {
+        entry.seconds = (entry.seconds * scale).round() / scale;
+    }
+    fs::write(path, to_json(&tempo_map, Path::new(path))?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    /// Make an empty directory for a test, and return the path.
+    pub(crate) fn scratch_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("rg_soundmap_{name}"));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn load_smap() {
+        let (manifest, soundmap, charts) = load_smap_dir("test_files/example").unwrap();
+
+        // Check manifest
+        assert_eq!(manifest.title, "Example");
+        assert_eq!(manifest.artists[0], "Example");
+        assert_eq!(manifest.get_sound_path(0).unwrap(), "kick.wav");
+
+        // Check soundmap
+        assert_eq!(soundmap.audio_format, "wav");
+        assert_eq!(soundmap.audio_bits, 24);
+        assert_eq!(soundmap.audio_sample_rate, 48000);
+        assert_eq!(soundmap.notes.len(), 4);
+        assert_eq!(soundmap.notes[0].time, 1056);
+        assert_eq!(soundmap.notes[1].time, 1320);
+
+        // Check charts
+        assert_eq!(charts.len(), 1);
+    }
+
+    #[test]
+    fn save_smap() {
+        let dir_name = "test_files/test";
+
+        // If the dir exists, delete it
+        if Path::new(dir_name).exists() {
+            fs::remove_dir_all(dir_name).unwrap();
+        }
+
+        let new_manifest = Manifest::new("Test", "Various Artists");
+        let new_soundmap = SoundMap::new();
+        let new_charts: Vec<Chart> = Vec::new();
+
+        save_smap_dir(
+            "test",
+            "test_files",
+            &new_manifest,
+            &new_soundmap,
+            &new_charts,
+        )
+        .unwrap();
+
+        // Recheck which saved
+        let (saved_manifest, saved_soundmap, saved_charts) = load_smap_dir(dir_name).unwrap();
+
+        // Check manifest
+        assert_eq!(saved_manifest.title, "Test");
+        assert_eq!(saved_manifest.artists[0], "Various Artists");
+
+        // Check soundmap
+        assert_eq!(saved_soundmap.audio_format, "wav");
+        assert_eq!(saved_soundmap.audio_bits, 24);
+        assert_eq!(saved_soundmap.audio_sample_rate, 48000);
+
+        // Check charts
+        assert_eq!(saved_charts.len(), 0);
+    }
+
+    #[test]
+    fn check_smap_valid() {
+        match check_smap("test_files/example") {
+            Ok(_) => (),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn save_grouped_charts() {
+        let dir = scratch_dir("save_grouped_charts");
+        let charts = vec![
+            Chart::new("Easy", "Tester").with_chart_type("4k"),
+            Chart::new("Hard", "Tester").with_chart_type("7K"),
+            Chart::new("Plain", "Tester"),
+        ];
+        save_smap_dir_with_options(
+            "smap",
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &SoundMap::new(),
+            &charts,
+            &SaveOptions::new().group_charts_by_type(),
+        )
+        .unwrap();
+        let smap_path = format!("{dir}/smap");
+
+        assert!(Path::new(&format!("{smap_path}/charts/4K/Easy.json")).exists());
+        assert!(Path::new(&format!("{smap_path}/charts/7K/Hard.json")).exists());
+        assert!(Path::new(&format!("{smap_path}/charts/Plain/Plain.json")).exists());
+
+        let (_manifest, _soundmap, mut saved_charts) = load_smap_dir(&smap_path).unwrap();
+        saved_charts.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(saved_charts.len(), 3);
+        assert_eq!(saved_charts[0].name, "Easy");
+        assert_eq!(saved_charts[1].name, "Hard");
+        check_smap(&smap_path).unwrap();
+
+        // Subdirectories are kept by pack
+        pack(&dir, "smap", "grouped.smap").unwrap();
+
+        // Entry names use `/` on every platform
+        let decoder = Decoder::new(File::open(format!("{dir}/grouped.smap")).unwrap()).unwrap();
+        let mut archive = tar::Archive::new(decoder);
+        let names: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| String::from_utf8(e.unwrap().path_bytes().to_vec()).unwrap())
+            .collect();
+        assert!(names.contains(&"charts/7K/Hard.json".to_string()));
+        assert!(names.iter().all(|name| !name.contains('\\')));
+
+        let unpack_path = format!("{dir}/unpack_result");
+        fs::create_dir(&unpack_path).unwrap();
+        unpack(&format!("{dir}/grouped.smap"), &unpack_path).unwrap();
+        assert!(Path::new(&format!("{unpack_path}/charts/7K/Hard.json")).exists());
+    }
+
+    #[test]
+    fn save_charts_with_unsafe_names() {
+        let dir = scratch_dir("save_unsafe_chart_names");
+        let charts = vec![
+            Chart::new("..", "Tester").with_custom_type(".."),
+            Chart::new("Normal", "Tester").with_custom_type("."),
+            Chart::new("", "Tester"),
+        ];
+        save_smap_dir_with_options(
+            "smap",
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &SoundMap::new(),
+            &charts,
+            &SaveOptions::new().group_charts_by_type(),
+        )
+        .unwrap();
+        let smap_path = format!("{dir}/smap");
+
+        // Charts are kept in the charts directory
+        assert!(Path::new(&format!("{smap_path}/charts/_/_.json")).exists());
+        assert!(Path::new(&format!("{smap_path}/charts/_/Normal.json")).exists());
+        assert!(Path::new(&format!("{smap_path}/charts/Plain/_.json")).exists());
+        assert_eq!(load_smap_dir(&smap_path).unwrap().2.len(), 3);
+    }
+
+    #[test]
+    fn load_smap_duplicated_note_ids() {
+        let dir = scratch_dir("load_duplicated_note_ids");
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(0, 0, 0);
+        soundmap.notes.push(soundmap.notes[0].clone());
+        let charts: Vec<Chart> = Vec::new();
+        save_smap_dir(
+            "smap",
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &soundmap,
+            &charts,
+        )
+        .unwrap();
+        let smap_path = format!("{dir}/smap");
+
+        assert!(load_smap_dir(&smap_path).is_err());
+        assert!(check_smap(&smap_path).is_err());
+
+        let (_manifest, raw_soundmap, _charts) = load_smap_dir_raw(&smap_path).unwrap();
+        assert_eq!(raw_soundmap.notes.len(), 2);
+    }
+
+    #[test]
+    fn resync_chart_names() {
+        let dir = scratch_dir("resync_chart_names");
+        let charts = vec![Chart::new("old", "Tester"), Chart::new("Keep", "Tester")];
+        save_smap_dir(
+            "smap",
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &SoundMap::new(),
+            &charts,
+        )
+        .unwrap();
+        let smap_path = format!("{dir}/smap");
+        let old_path = PathBuf::from(format!("{smap_path}/charts/old.json"));
+        let new_path = PathBuf::from(format!("{smap_path}/charts/New.json"));
+
+        // Edit the name
+        let mut chart = charts[0].clone();
+        chart.name = "New".to_string();
+        fs::write(&old_path, serde_json::to_string(&chart).unwrap()).unwrap();
+
+        let renames = resync_chart_filenames(&smap_path).unwrap();
+        assert_eq!(renames, vec![(old_path.clone(), new_path.clone())]);
+        assert!(!old_path.exists());
+        assert!(new_path.exists());
+        assert!(Path::new(&format!("{smap_path}/charts/Keep.json")).exists());
+
+        // Already synced
+        assert!(resync_chart_filenames(&smap_path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn chart_name_mismatch() {
+        let dir = scratch_dir("chart_name_mismatch");
+        save_smap_dir(
+            "smap",
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &SoundMap::new(),
+            &[Chart::new("Keep", "Tester")],
+        )
+        .unwrap();
+        let smap_path = format!("{dir}/smap");
+        let cool_path = PathBuf::from(format!("{smap_path}/charts/cool.json"));
+        let chart = Chart::new("Cool Chart", "Tester");
+        fs::write(&cool_path, serde_json::to_string(&chart).unwrap()).unwrap();
+
+        assert_eq!(
+            check_chart_filenames(&smap_path).unwrap(),
+            vec![(
+                cool_path.clone(),
+                PathBuf::from(format!("{smap_path}/charts/Cool Chart.json"))
+            )]
+        );
+        // Nothing is renamed
+        assert!(cool_path.exists());
+
+        let ((_manifest, _soundmap, charts), mismatches) =
+            load_smap_dir_with_report(&smap_path).unwrap();
+        assert_eq!(charts.len(), 2);
+        assert_eq!(mismatches, check_chart_filenames(&smap_path).unwrap());
+
+        let result = ingest_smap_dir(&smap_path).unwrap();
+        assert_eq!(result.charts.len(), 2);
+        assert_eq!(result.warnings.len(), 1);
+        assert_eq!(
+            result.warnings[0].file.as_deref(),
+            Some(Path::new("charts/cool.json"))
+        );
+        assert!(result.warnings[0].message.contains("`Cool Chart.json`"));
+
+        resync_chart_filenames(&smap_path).unwrap();
+        assert!(check_chart_filenames(&smap_path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn resync_chart_names_collision() {
+        let dir = scratch_dir("resync_chart_names_collision");
+        let charts = vec![
+            Chart::new("First", "Tester"),
+            Chart::new("Second", "Tester"),
+        ];
+        save_smap_dir(
+            "smap",
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &SoundMap::new(),
+            &charts,
+        )
+        .unwrap();
+        let smap_path = format!("{dir}/smap");
+
+        // Both charts are named "First"
+        let second_path = format!("{smap_path}/charts/Second.json");
+        fs::write(&second_path, serde_json::to_string(&charts[0]).unwrap()).unwrap();
+
+        assert!(matches!(
+            resync_chart_filenames(&smap_path),
+            Err(SmapError::FileCollision(_))
+        ));
+        assert!(Path::new(&second_path).exists());
+    }
+
+    #[test]
+    fn ingest_smap() {
+        let result = ingest_smap_dir("test_files/example").unwrap();
+        assert_eq!(result.manifest.title, "Example");
+        assert_eq!(result.charts.len(), 1);
+        assert!(result.warnings.is_empty());
+
+        // Same contents, same hash
+        let again = ingest_smap_dir("test_files/example").unwrap();
+        assert_eq!(result.hash, again.hash);
+
+        let mut soundmap = result.soundmap.clone();
+        soundmap.notes[0].time += 1;
+        assert_ne!(
+            result.hash,
+            content_hash(&result.manifest, &soundmap, &result.charts)
+        );
+    }
+
+    #[test]
+    fn ingest_smap_invalid() {
+        let dir = scratch_dir("ingest_invalid");
+        assert!(matches!(ingest_smap_dir(&dir), Err(SmapError::Check(_))));
+    }
+
+    // Pack and unpack soundmap test
+    #[test]
+    fn pack_smap() {
+        let test_dir = "test_files";
+        let smap_name = "pack_test";
+        let filename = format!("{smap_name}.smap");
+        let smap_file_path = format!("{test_dir}/{filename}");
+        let smap_result_path = format!("{test_dir}/unpack_result");
+
+        // If the file and directory exists, delete it
+        let smap_path = format!("{test_dir}/{smap_name}");
+        if Path::new(&smap_path).exists() {
+            fs::remove_dir_all(&smap_path).unwrap();
+        }
+        if Path::new(&smap_file_path).exists() {
+            fs::remove_file(&smap_file_path).unwrap();
+        }
+        if Path::new(&smap_result_path).exists() {
+            fs::remove_dir_all(&smap_result_path).unwrap();
+        }
+
+        let new_manifest = Manifest::new("Test", "Various Artists");
+        let new_soundmap = SoundMap::new();
+        let new_charts: Vec<Chart> = Vec::new();
+
+        save_smap_dir(
+            smap_name,
+            test_dir,
+            &new_manifest,
+            &new_soundmap,
+            &new_charts,
+        )
+        .unwrap();
+
+        pack(test_dir, smap_name, &filename).unwrap();
+        // The source is kept
+        assert!(Path::new(&format!("{smap_path}/manifest.json")).exists());
+        fs::remove_dir_all(&smap_path).unwrap();
+
+        // Unpack `*.smap` file
+        fs::create_dir(&smap_result_path).unwrap();
+        unpack(&smap_file_path, &smap_result_path).unwrap();
+
+        // Recheck which saved
         let (saved_manifest, saved_soundmap, saved_charts) =
             load_smap_dir(&smap_result_path).unwrap();
 
@@ -322,4 +1589,854 @@ mod tests {
         // Check charts
         assert_eq!(saved_charts.len(), 0);
     }
+
+    #[test]
+    fn pack_and_consume_smap() {
+        let dir = scratch_dir("pack_and_consume");
+        save_smap_dir(
+            "smap",
+            &dir,
+            &Manifest::new("Consumed", "Various Artists"),
+            &SoundMap::new(),
+            &[],
+        )
+        .unwrap();
+
+        pack_and_consume(&dir, "smap", "consumed.smap").unwrap();
+        assert!(!Path::new(&format!("{dir}/smap")).exists());
+        let (manifest, _soundmap, _charts) =
+            load_smap_bytes(&fs::read(format!("{dir}/consumed.smap")).unwrap()).unwrap();
+        assert_eq!(manifest.title, "Consumed");
+    }
+
+    #[test]
+    fn pack_smap_from_memory() {
+        let dir = scratch_dir("pack_from_memory");
+        let smap_file_path = format!("{dir}/memory.smap");
+        let smap_result_path = format!("{dir}/unpack_result");
+
+        let mut manifest = Manifest::new("Memory", "Various Artists");
+        manifest.push_sound("kick.wav", 36);
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(0, 0, 0);
+        let mut chart = Chart::new("Normal", "Tester");
+        chart.insert_note(0, 0);
+        let sounds = HashMap::from([(0, b"RIFF".to_vec())]);
+
+        pack_from_memory(&smap_file_path, &manifest, &soundmap, &[chart], &sounds).unwrap();
+
+        fs::create_dir(&smap_result_path).unwrap();
+        unpack(&smap_file_path, &smap_result_path).unwrap();
+
+        let (saved_manifest, saved_soundmap, saved_charts) =
+            load_smap_dir(&smap_result_path).unwrap();
+        assert_eq!(saved_manifest.title, "Memory");
+        assert_eq!(saved_soundmap.notes.len(), 1);
+        assert_eq!(saved_charts.len(), 1);
+        assert_eq!(saved_charts[0].name, "Normal");
+        assert_eq!(
+            fs::read(format!("{smap_result_path}/sounds/kick.wav")).unwrap(),
+            b"RIFF"
+        );
+    }
+
+    #[test]
+    fn pack_from_memory_unknown_sound() {
+        let dir = scratch_dir("pack_from_memory_unknown");
+        let sounds = HashMap::from([(3, Vec::new())]);
+
+        let result = pack_from_memory(
+            &format!("{dir}/memory.smap"),
+            &Manifest::new("Memory", "Various Artists"),
+            &SoundMap::new(),
+            &[],
+            &sounds,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn unpack_sounds_in_order() {
+        let dir = scratch_dir("unpack_in_order");
+        let smap_file_path = format!("{dir}/ordered.smap");
+        let smap_result_path = format!("{dir}/unpack_result");
+
+        let mut manifest = Manifest::new("Ordered", "Various Artists");
+        manifest.push_sound("snare.wav", 38);
+        manifest.push_sound("kick.wav", 36);
+        manifest.sounds.reverse();
+        assert_eq!(
+            sounds_in_manifest_order(&manifest),
+            vec!["snare.wav", "kick.wav"]
+        );
+
+        let sounds = HashMap::from([(0, b"snare".to_vec()), (1, b"kick".to_vec())]);
+        pack_from_memory(&smap_file_path, &manifest, &SoundMap::new(), &[], &sounds).unwrap();
+
+        fs::create_dir(&smap_result_path).unwrap();
+        let order = unpack_in_manifest_order(&smap_file_path, &smap_result_path).unwrap();
+        assert_eq!(order, vec!["snare.wav", "kick.wav"]);
+
+        let saved_order = fs::read_to_string(format!("{smap_result_path}/order.json")).unwrap();
+        let saved_order: Vec<String> = serde_json::from_str(&saved_order).unwrap();
+        assert_eq!(saved_order, order);
+        assert_eq!(
+            fs::read(format!("{smap_result_path}/sounds/kick.wav")).unwrap(),
+            b"kick"
+        );
+        load_smap_dir(&smap_result_path).unwrap();
+        assert!(!Path::new(&format!("{smap_result_path}/_temp.tar")).exists());
+    }
+
+    #[test]
+    fn unpack_in_order_rejects_escaping_entries() {
+        let dir = scratch_dir("unpack_in_order_escaping");
+        let smap_file_path = format!("{dir}/escaping.smap");
+        let smap_result_path = format!("{dir}/unpack_result");
+
+        // `set_path` rejects `..`, so write the name directly
+        let mut archive = tar::Builder::new(Vec::new());
+        let manifest = serde_json::to_vec(&Manifest::new("Evil", "Tester")).unwrap();
+        append_tar_data(&mut archive, "manifest.json", &manifest).unwrap();
+        let mut header = tar::Header::new_gnu();
+        let name = b"sounds/../../escaped.txt";
+        header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name);
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive.append(&header, &b"evil"[..]).unwrap();
+        let archive = archive.into_inner().unwrap();
+
+        let mut encoder = EncoderBuilder::new()
+            .build(File::create(&smap_file_path).unwrap())
+            .unwrap();
+        encoder.write_all(&archive).unwrap();
+        encoder.finish().1.unwrap();
+
+        fs::create_dir_all(&smap_result_path).unwrap();
+        assert!(matches!(
+            unpack_in_manifest_order(&smap_file_path, &smap_result_path),
+            Err(SmapError::InvalidArchive(_))
+        ));
+        assert!(!Path::new(&format!("{dir}/escaped.txt")).exists());
+    }
+
+    #[test]
+    fn verify_archive() {
+        let dir = scratch_dir("verify_archive");
+        let smap_file_path = format!("{dir}/verify.smap");
+        let mut soundmap = SoundMap::new();
+        for time in 0..1000 {
+            soundmap.insert_note(0, time * 48, 0);
+        }
+        pack_from_memory(
+            &smap_file_path,
+            &Manifest::new("Verify", "Various Artists"),
+            &soundmap,
+            &[],
+            &HashMap::new(),
+        )
+        .unwrap();
+        verify_smap_archive(&smap_file_path).unwrap();
+
+        // Truncated
+        let data = fs::read(&smap_file_path).unwrap();
+        let truncated_path = format!("{dir}/truncated.smap");
+        fs::write(&truncated_path, &data[..data.len() / 2]).unwrap();
+        assert!(matches!(
+            verify_smap_archive(&truncated_path),
+            Err(SmapError::InvalidArchive(_))
+        ));
+
+        // Not lz4
+        let garbage_path = format!("{dir}/garbage.smap");
+        fs::write(&garbage_path, b"not an archive").unwrap();
+        assert!(verify_smap_archive(&garbage_path).is_err());
+    }
+
+    #[test]
+    fn list_pack_entries() {
+        let dir = scratch_dir("list_pack_contents");
+        let charts = vec![Chart::new("Hard", "Tester"), Chart::new("Easy", "Tester")];
+        save_smap_dir(
+            "smap",
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &SoundMap::new(),
+            &charts,
+        )
+        .unwrap();
+        fs::write(format!("{dir}/smap/sounds/kick.wav"), b"RIFF").unwrap();
+
+        let contents = list_pack_contents(&format!("{dir}/smap")).unwrap();
+        assert_eq!(
+            contents,
+            vec![
+                "manifest.json",
+                "content.json",
+                "charts",
+                "sounds",
+                "charts/Easy.json",
+                "charts/Hard.json",
+                "sounds/kick.wav",
+            ]
+        );
+
+        // Compare with the archive
+        pack(&dir, "smap", "list.smap").unwrap();
+        let decoder = Decoder::new(File::open(format!("{dir}/list.smap")).unwrap()).unwrap();
+        let mut archive = tar::Archive::new(decoder);
+        let archived: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(archived, contents);
+    }
+
+    /// Pack a small soundmap in memory, and return bytes of the `*.smap` file.
+    fn packed_bytes(name: &str) -> Vec<u8> {
+        let dir = scratch_dir(name);
+        let smap_file_path = format!("{dir}/bytes.smap");
+
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(0, 0, 0);
+        let mut chart = Chart::new("Normal", "Tester");
+        chart.insert_note(0, 0);
+        pack_from_memory(
+            &smap_file_path,
+            &Manifest::new("Bytes", "Various Artists"),
+            &soundmap,
+            &[chart],
+            &HashMap::new(),
+        )
+        .unwrap();
+        fs::read(&smap_file_path).unwrap()
+    }
+
+    #[test]
+    fn pack_and_unpack_in_memory() {
+        let dir = scratch_dir("pack_in_memory");
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(0, 0, 0);
+        let charts = vec![Chart::new("Normal", "Tester")];
+        save_smap_dir(
+            "smap",
+            &dir,
+            &Manifest::new("Memory", "Various Artists"),
+            &soundmap,
+            &charts,
+        )
+        .unwrap();
+        fs::write(format!("{dir}/smap/sounds/kick.wav"), b"RIFF").unwrap();
+
+        let smap_dir_path = format!("{dir}/smap");
+        let bytes = pack_to_bytes(&smap_dir_path, &PackOptions::new()).unwrap();
+        // The source is kept, and no temporary file is written
+        assert!(Path::new(&smap_dir_path).exists());
+        assert!(!Path::new(&format!("{dir}/_temp.tar")).exists());
+
+        let (manifest, _soundmap, charts) = load_smap_bytes(&bytes).unwrap();
+        assert_eq!(manifest.title, "Memory");
+        assert_eq!(charts[0].name, "Normal");
+
+        let unpacked_path = format!("{dir}/unpacked");
+        unpack_from_bytes(&bytes, &unpacked_path).unwrap();
+        assert_eq!(
+            fs::read(format!("{unpacked_path}/sounds/kick.wav")).unwrap(),
+            b"RIFF"
+        );
+        assert!(!Path::new(&format!("{unpacked_path}/_temp.tar")).exists());
+        load_smap_dir(&unpacked_path).unwrap();
+
+        // Same bytes as `pack`
+        let options = PackOptions::new().deterministic();
+        let written = pack_to_writer(&smap_dir_path, Vec::new(), &options).unwrap();
+        pack_with_options(&dir, "smap", "packed.smap", &options).unwrap();
+        assert_eq!(written, fs::read(format!("{dir}/packed.smap")).unwrap());
+    }
+
+    #[test]
+    fn load_smap_from_bytes() {
+        let (manifest, soundmap, charts) = load_smap_bytes(&packed_bytes("load_bytes")).unwrap();
+        assert_eq!(manifest.title, "Bytes");
+        assert_eq!(soundmap.notes.len(), 1);
+        assert_eq!(charts[0].name, "Normal");
+
+        assert!(matches!(
+            load_smap_bytes(b"not a smap"),
+            Err(SmapError::InvalidArchive(_))
+        ));
+    }
+
+    #[test]
+    fn load_smap_bytes_skips_non_json_charts() {
+        let mut archive = tar::Builder::new(Vec::new());
+        let manifest = serde_json::to_vec(&Manifest::new("Bytes", "Various Artists")).unwrap();
+        let soundmap = serde_json::to_vec(&SoundMap::new()).unwrap();
+        let chart = serde_json::to_vec(&Chart::new("Normal", "Tester")).unwrap();
+        append_tar_data(&mut archive, "manifest.json", &manifest).unwrap();
+        append_tar_data(&mut archive, "content.json", &soundmap).unwrap();
+        append_tar_data(&mut archive, "charts/Normal.json", &chart).unwrap();
+        append_tar_data(&mut archive, "charts/.DS_Store", b"\0\0\0\x01Bud1").unwrap();
+
+        let mut encoder = EncoderBuilder::new().build(Vec::new()).unwrap();
+        encoder.write_all(&archive.into_inner().unwrap()).unwrap();
+        let (bytes, result) = encoder.finish();
+        result.unwrap();
+
+        let (_manifest, _soundmap, charts) = load_smap_bytes(&bytes).unwrap();
+        assert_eq!(charts.len(), 1);
+        assert_eq!(charts[0].name, "Normal");
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[test]
+    fn load_smap_from_url() {
+        use std::net::TcpListener;
+
+        let smap = packed_bytes("load_url");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // Serve the file, 404, then the file again for the size limit
+        let server = std::thread::spawn(move || {
+            for (index, stream) in listener.incoming().take(3).enumerate() {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).unwrap();
+
+                let (status, body) = if index == 1 {
+                    ("404 Not Found", &b""[..])
+                } else {
+                    ("200 OK", smap.as_slice())
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+
+        let url = format!("http://{address}/song.smap");
+        let (manifest, _, charts) = load_smap_url(&url).unwrap();
+        assert_eq!(manifest.title, "Bytes");
+        assert_eq!(charts.len(), 1);
+
+        let error = load_smap_url(&url).unwrap_err();
+        assert!(error.to_string().contains("404"));
+
+        let error = load_smap_url_with_limit(&url, 10).unwrap_err();
+        assert!(matches!(
+            &error,
+            SmapError::Download(message) if message.ends_with("is larger than 10 bytes")
+        ));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn export_tempo_map_file() {
+        let dir = scratch_dir("export_tempo_map");
+        let path = format!("{dir}/tempo.json");
+
+        let mut soundmap = SoundMap::new();
+        soundmap.bpm.push(types::soundmap::Bpm::new(150.0, 1536));
+        export_tempo_map(&soundmap, &path).unwrap();
+
+        let entries: Vec<types::soundmap::TempoMapEntry> =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        // The first BPM and meter are merged
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].beat_per_bar, Some(4));
+        assert_eq!(entries[1].bpm, Some(150.0));
+        assert_eq!((entries[1].bar, entries[1].beat), (3, 1.0));
+        assert_eq!(entries[1].seconds, 4.0);
+    }
+
+    #[test]
+    fn load_smap_empty_file() {
+        let dir = scratch_dir("load_empty_file");
+        save_smap_dir(
+            "empty",
+            &dir,
+            &Manifest::new("Empty", "Various Artists"),
+            &SoundMap::new(),
+            &[],
+        )
+        .unwrap();
+        let smap_path = format!("{dir}/empty");
+        fs::write(format!("{smap_path}/content.json"), " \n").unwrap();
+
+        let error = load_smap_dir(&smap_path).unwrap_err();
+        assert!(matches!(
+            error,
+            SmapError::EmptyFile(path) if path.ends_with("content.json")
+        ));
+
+        let error = check_smap(&smap_path).unwrap_err().to_string();
+        assert!(error.contains("File is empty"));
+    }
+
+    #[test]
+    fn read_manifest_only() {
+        let dir = scratch_dir("read_manifest_only");
+        let smap_file_path = format!("{dir}/manifest_first.smap");
+
+        // Entries after the manifest are broken
+        let mut builder = tar::Builder::new(Vec::new());
+        let manifest = serde_json::to_vec(&Manifest::new("Listed", "Tester")).unwrap();
+        append_tar_data(&mut builder, "manifest.json", &manifest).unwrap();
+        let mut tar = builder.into_inner().unwrap();
+        tar.extend_from_slice(&[0xFF; 1024]);
+
+        let mut encoder = EncoderBuilder::new()
+            .build(File::create(&smap_file_path).unwrap())
+            .unwrap();
+        encoder.write_all(&tar).unwrap();
+        encoder.finish().1.unwrap();
+
+        let manifest = read_manifest_from_smap(&smap_file_path).unwrap();
+        assert_eq!(manifest.title, "Listed");
+        assert!(matches!(
+            load_smap_bytes(&fs::read(&smap_file_path).unwrap()),
+            Err(SmapError::InvalidArchive(_))
+        ));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // Without manifest
+        let mut builder = tar::Builder::new(Vec::new());
+        append_tar_data(&mut builder, "content.json", b"{}").unwrap();
+        let mut encoder = EncoderBuilder::new()
+            .build(File::create(&smap_file_path).unwrap())
+            .unwrap();
+        encoder.write_all(&builder.into_inner().unwrap()).unwrap();
+        encoder.finish().1.unwrap();
+        assert!(matches!(
+            read_manifest_from_smap(&smap_file_path),
+            Err(SmapError::InvalidArchive(_))
+        ));
+    }
+
+    #[test]
+    fn read_one_chart() {
+        let dir = scratch_dir("read_one_chart");
+        let smap_file_path = format!("{dir}/charts.smap");
+        let mut hard = Chart::new("Hard", "Tester").with_level(9);
+        hard.insert_silent_note(0, 0);
+        pack_from_memory(
+            &smap_file_path,
+            &Manifest::new("Charts", "Various Artists"),
+            &SoundMap::new(),
+            &[Chart::new("Easy", "Tester"), hard],
+            &HashMap::new(),
+        )
+        .unwrap();
+
+        let chart = read_chart_from_smap(&smap_file_path, "Hard").unwrap();
+        assert_eq!(chart.difficulty_level, 9);
+        assert_eq!(chart.content.len(), 1);
+        assert!(matches!(
+            read_chart_from_smap(&smap_file_path, "Normal"),
+            Err(SmapError::ChartNotFound(name)) if name == "Normal"
+        ));
+    }
+
+    #[test]
+    fn unpack_plain_tar() {
+        let dir = scratch_dir("unpack_plain_tar");
+        let tar_path = format!("{dir}/plain.tar");
+
+        let mut builder = tar::Builder::new(File::create(&tar_path).unwrap());
+        builder.append_dir_all(".", "test_files/example").unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        let (manifest, soundmap, charts) =
+            load_smap_tar(&tar_path, &format!("{dir}/loaded")).unwrap();
+        assert_eq!(manifest.title, "Example");
+        assert_eq!(soundmap.notes.len(), 4);
+        assert_eq!(charts.len(), 1);
+
+        // Detected by `unpack`
+        let unpacked = format!("{dir}/unpacked");
+        fs::create_dir(&unpacked).unwrap();
+        unpack(&tar_path, &unpacked).unwrap();
+        load_smap_dir(&unpacked).unwrap();
+    }
+
+    #[test]
+    fn unpack_limited() {
+        let dir = scratch_dir("unpack_limited");
+        let smap_file_path = format!("{dir}/many.smap");
+        let sounds: HashMap<u16, Vec<u8>> = (0..100).map(|id| (id, vec![0; 10])).collect();
+        let mut manifest = Manifest::new("Many", "Various Artists");
+        for id in 0..100 {
+            manifest.push_sound(&format!("{id}.wav"), 36);
+        }
+        pack_from_memory(&smap_file_path, &manifest, &SoundMap::new(), &[], &sounds).unwrap();
+
+        let unpacked = format!("{dir}/unpacked");
+        fs::create_dir(&unpacked).unwrap();
+        let limits = UnpackLimits::new().with_max_entries(20);
+        assert!(matches!(
+            unpack_with_limits(&smap_file_path, &unpacked, &limits),
+            Err(SmapError::TooManyEntries(20))
+        ));
+
+        let limits = UnpackLimits::new().with_max_bytes(100);
+        assert!(matches!(
+            unpack_with_limits(&smap_file_path, &unpacked, &limits),
+            Err(SmapError::TooLarge(100))
+        ));
+
+        unpack_with_limits(&smap_file_path, &unpacked, &UnpackLimits::new()).unwrap();
+        load_smap_dir(&unpacked).unwrap();
+    }
+
+    #[test]
+    fn iterate_charts() {
+        let charts: Vec<Chart> = iter_charts("test_files/example")
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(charts.len(), 1);
+
+        let mut charts = iter_charts("test_files/nothing");
+        assert!(matches!(charts.next(), Some(Err(SmapError::Io(_)))));
+        assert!(charts.next().is_none());
+    }
+
+    #[test]
+    fn generator_stamp() {
+        let dir = scratch_dir("generator_stamp");
+        let charts: Vec<Chart> = Vec::new();
+        let manifest = Manifest::new("Stamp", "Various Artists");
+        save_smap_dir("stamped", &dir, &manifest, &SoundMap::new(), &charts).unwrap();
+        let manifest = manifest.with_generator("Other Tool 1.0");
+        save_smap_dir("overridden", &dir, &manifest, &SoundMap::new(), &charts).unwrap();
+
+        let (stamped, _, _) = load_smap_dir(&format!("{dir}/stamped")).unwrap();
+        assert_eq!(
+            stamped.generator.as_deref(),
+            Some(types::manifest::GENERATOR)
+        );
+        assert!(stamped.generator.unwrap().starts_with("rg_soundmap "));
+
+        let (overridden, _, _) = load_smap_dir(&format!("{dir}/overridden")).unwrap();
+        assert_eq!(overridden.generator.as_deref(), Some("Other Tool 1.0"));
+    }
+
+    #[test]
+    fn pack_levels() {
+        let dir = scratch_dir("pack_levels");
+        save_smap_dir(
+            "smap",
+            &dir,
+            &Manifest::new("Levels", "Various Artists"),
+            &SoundMap::new(),
+            &[],
+        )
+        .unwrap();
+
+        // Compressible, but not too simple
+        let mut seed: u32 = 1;
+        let sound: Vec<u8> = (0..256 * 1024)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                b"abcdefgh"[(seed >> 28) as usize % 8]
+            })
+            .collect();
+        fs::write(format!("{dir}/smap/sounds/noise.wav"), sound).unwrap();
+
+        pack_with_level(&dir, "smap", "fast.smap", 1).unwrap();
+        pack_with_level(&dir, "smap", "small.smap", 16).unwrap();
+        let fast = fs::read(format!("{dir}/fast.smap")).unwrap();
+        let small = fs::read(format!("{dir}/small.smap")).unwrap();
+        assert!(small.len() < fast.len());
+        assert_eq!(
+            load_smap_bytes(&small).unwrap().0.title,
+            load_smap_bytes(&fast).unwrap().0.title
+        );
+
+        assert!(matches!(
+            pack_with_level(&dir, "smap", "invalid.smap", 17),
+            Err(SmapError::InvalidLevel(17))
+        ));
+        assert!(!Path::new(&format!("{dir}/invalid.smap")).exists());
+    }
+
+    #[test]
+    fn pack_deterministic() {
+        let dir = scratch_dir("pack_deterministic");
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(0, 0, 0);
+        let charts = vec![Chart::new("Normal", "Tester")];
+        let manifest = Manifest::new("Same", "Various Artists");
+
+        save_smap_dir("same", &dir, &manifest, &soundmap, &charts).unwrap();
+        let mut packed = Vec::new();
+        for modified in [UNIX_EPOCH, UNIX_EPOCH + Duration::from_secs(86400)] {
+            File::options()
+                .write(true)
+                .open(format!("{dir}/same/manifest.json"))
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+
+            let options = PackOptions::new().deterministic();
+            pack_with_options(&dir, "same", "same.smap", &options).unwrap();
+            packed.push(fs::read(format!("{dir}/same.smap")).unwrap());
+        }
+        assert_eq!(packed[0], packed[1]);
+    }
+
+    #[test]
+    fn unpack_missing_sounds() {
+        let dir = scratch_dir("unpack_verify_manifest");
+        let smap_file_path = format!("{dir}/missing.smap");
+        let smap_result_path = format!("{dir}/unpack_result");
+
+        let mut manifest = Manifest::new("Missing", "Various Artists");
+        manifest.push_sound("kick.wav", 36);
+        manifest.push_sound("forgotten.wav", 38);
+        let sounds = HashMap::from([(0, b"kick".to_vec())]);
+        pack_from_memory(&smap_file_path, &manifest, &SoundMap::new(), &[], &sounds).unwrap();
+
+        fs::create_dir(&smap_result_path).unwrap();
+        let missing = unpack_verify_manifest(&smap_file_path, &smap_result_path).unwrap();
+        assert_eq!(missing, vec!["forgotten.wav"]);
+    }
+
+    #[test]
+    fn export_tempo_map_precision() {
+        let dir = scratch_dir("export_tempo_map_precision");
+        let path = format!("{dir}/tempo.json");
+
+        let mut soundmap = SoundMap::new();
+        soundmap.bpm.push(types::soundmap::Bpm::new(140.0, 1000));
+        soundmap.bpm.push(types::soundmap::Bpm::new(133.0, 2000));
+        export_tempo_map_with_precision(&soundmap, &path, 3).unwrap();
+
+        let entries: Vec<types::soundmap::TempoMapEntry> =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(entries.len(), 3);
+        for entry in &entries {
+            let decimals = entry
+                .seconds
+                .to_string()
+                .split_once('.')
+                .map_or(0, |(_, d)| d.len());
+            assert!(decimals <= 3, "{}", entry.seconds);
+        }
+        assert_eq!(entries[2].seconds, 4.836);
+    }
+
+    #[test]
+    fn save_many_charts() {
+        let dir = scratch_dir("save_many_charts");
+        let charts: Vec<Chart> = (0..100)
+            .map(|i| Chart::new(&format!("Chart {i}"), "Tester").with_level(i as u8))
+            .collect();
+        save_smap_dir(
+            "many",
+            &dir,
+            &Manifest::new("Many", "Various Artists"),
+            &SoundMap::new(),
+            &charts,
+        )
+        .unwrap();
+
+        let (_, _, mut loaded) = load_smap_dir(&format!("{dir}/many")).unwrap();
+        assert_eq!(loaded.len(), 100);
+        loaded.sort_by_key(|c| c.difficulty_level);
+        for (i, chart) in loaded.iter().enumerate() {
+            assert_eq!(chart.name, format!("Chart {i}"));
+        }
+    }
+
+    #[test]
+    fn load_smap_missing_files() {
+        let dir = scratch_dir("load_smap_missing_files");
+        assert!(matches!(
+            load_smap_dir(&dir),
+            Err(SmapError::MissingManifest)
+        ));
+
+        fs::copy(
+            "test_files/example/manifest.json",
+            format!("{dir}/manifest.json"),
+        )
+        .unwrap();
+        assert!(matches!(
+            load_smap_dir(&dir),
+            Err(SmapError::MissingSoundmap)
+        ));
+
+        fs::copy(
+            "test_files/example/content.json",
+            format!("{dir}/content.json"),
+        )
+        .unwrap();
+        assert!(matches!(
+            load_smap_dir(&dir),
+            Err(SmapError::MissingChartsDir)
+        ));
+
+        fs::create_dir(format!("{dir}/charts")).unwrap();
+        load_smap_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_smap_skips_other_files() {
+        let dir = scratch_dir("load_smap_skips_other_files");
+        let charts = vec![Chart::new("Normal", "Tester")];
+        save_smap_dir(
+            "smap",
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &SoundMap::new(),
+            &charts,
+        )
+        .unwrap();
+        let smap_path = format!("{dir}/smap");
+        fs::write(format!("{smap_path}/charts/.DS_Store"), [0, 0, 0, 1]).unwrap();
+
+        let (_manifest, _soundmap, charts) = load_smap_dir(&smap_path).unwrap();
+        assert_eq!(charts.len(), 1);
+        assert_eq!(iter_charts(&smap_path).count(), 1);
+
+        let report = inspect_smap(&smap_path);
+        assert!(report.is_ok());
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].message.contains("not a chart"));
+    }
+
+    #[test]
+    fn load_smap_without_bpm() {
+        let dir = scratch_dir("load_smap_without_bpm");
+        let mut soundmap = SoundMap::from_notes([(0, 0, 0)]);
+        soundmap.bpm.clear();
+        save_smap_dir(
+            "smap",
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &soundmap,
+            &[],
+        )
+        .unwrap();
+        let smap_path = format!("{dir}/smap");
+        let content = fs::read_to_string(format!("{smap_path}/content.json")).unwrap();
+        assert!(content.contains("\"bpm\": []"));
+
+        let (_manifest, loaded, _charts) = load_smap_dir(&smap_path).unwrap();
+        assert_eq!(loaded.bpm, vec![types::soundmap::Bpm::new(120.0, 0)]);
+        let (_manifest, raw, _charts) = load_smap_dir_raw(&smap_path).unwrap();
+        assert!(raw.bpm.is_empty());
+
+        let report = inspect_smap(&smap_path);
+        assert!(report.warnings.iter().any(|w| w.message.contains("no BPM")));
+    }
+
+    #[test]
+    fn save_duplicated_chart_names() {
+        let dir = scratch_dir("save_duplicated_chart_names");
+        let charts = vec![Chart::new("Hard", "Tester"), Chart::new("Hard", "Other")];
+        let error = save_smap_dir(
+            "duplicated",
+            &dir,
+            &Manifest::new("Test", "Various Artists"),
+            &SoundMap::new(),
+            &charts,
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            error,
+            SmapError::FileCollision(path) if path.ends_with("Hard.json")
+        ));
+        assert!(!Path::new(&format!("{dir}/duplicated")).exists());
+    }
+
+    #[test]
+    fn save_into_nested_path() {
+        let dir = scratch_dir("save_into_nested_path");
+        let nested = format!("{dir}/not/created/yet");
+        let charts = vec![Chart::new("Hard", "Tester")];
+        save_smap_dir(
+            "smap",
+            &nested,
+            &Manifest::new("Nested", "Various Artists"),
+            &SoundMap::new(),
+            &charts,
+        )
+        .unwrap();
+
+        let (manifest, _soundmap, charts) = load_smap_dir(&format!("{nested}/smap")).unwrap();
+        assert_eq!(manifest.title, "Nested");
+        assert_eq!(charts.len(), 1);
+    }
+
+    #[test]
+    fn save_over_existing_dir() {
+        let dir = scratch_dir("save_over_existing_dir");
+        let charts = vec![Chart::new("Old", "Tester")];
+        save_smap_dir(
+            "smap",
+            &dir,
+            &Manifest::new("Old", "Various Artists"),
+            &SoundMap::new(),
+            &charts,
+        )
+        .unwrap();
+
+        let charts = vec![Chart::new("New", "Tester")];
+        let manifest = Manifest::new("New", "Various Artists");
+        let error = save_smap_dir("smap", &dir, &manifest, &SoundMap::new(), &charts).unwrap_err();
+        assert!(matches!(error, SmapError::AlreadyExists(path) if path.ends_with("smap")));
+        // Nothing is changed
+        assert!(Path::new(&format!("{dir}/smap/charts/Old.json")).exists());
+
+        save_smap_dir_overwrite("smap", &dir, &manifest, &SoundMap::new(), &charts).unwrap();
+        let (saved_manifest, _soundmap, saved_charts) =
+            load_smap_dir(&format!("{dir}/smap")).unwrap();
+        assert_eq!(saved_manifest.title, "New");
+        assert_eq!(saved_charts.len(), 1);
+        assert_eq!(saved_charts[0].name, "New");
+    }
+
+    #[test]
+    fn load_smap_with_bom() {
+        let dir = scratch_dir("load_with_bom");
+        let charts: Vec<Chart> = Vec::new();
+        let manifest = Manifest::new("Bom", "Various Artists");
+        save_smap_dir("smap", &dir, &manifest, &SoundMap::new(), &charts).unwrap();
+        let smap_path = format!("{dir}/smap");
+        let manifest_path = format!("{smap_path}/manifest.json");
+
+        let mut data = vec![0xEF, 0xBB, 0xBF];
+        data.extend(serde_json::to_vec(&manifest).unwrap());
+        fs::write(&manifest_path, &data).unwrap();
+
+        let (saved_manifest, _soundmap, _charts) = load_smap_dir(&smap_path).unwrap();
+        assert_eq!(saved_manifest.title, "Bom");
+        check_smap(&smap_path).unwrap();
+
+        // UTF-16 (LE)
+        let mut data = vec![0xFF, 0xFE];
+        for unit in serde_json::to_string(&manifest).unwrap().encode_utf16() {
+            data.extend(unit.to_le_bytes());
+        }
+        fs::write(&manifest_path, &data).unwrap();
+
+        let error = load_smap_dir(&smap_path).unwrap_err();
+        assert!(error.to_string().contains("UTF-16"));
+        assert!(
+            check_smap(&smap_path)
+                .unwrap_err()
+                .to_string()
+                .contains("UTF-16")
+        );
+    }
 }
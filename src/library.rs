@@ -0,0 +1,196 @@
+//! Library index
+//!
+//! A compact on-disk index of packed soundmaps, so a library can be searched without
+//! decompressing every archive on launch.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::error::SmapError;
+use crate::read_manifest_from_smap;
+
+/// An indexed `*.smap` file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexEntry {
+    /// A title of the song
+    pub title: String,
+
+    /// A list of artists
+    pub artists: Vec<String>,
+
+    pub genre: String,
+
+    /// A path of the `*.smap` file
+    pub path: String,
+
+    /// Modified time of the file when it was indexed. (Seconds since UNIX epoch)
+    pub modified: u64,
+
+    /// Nanoseconds of `modified`, so a file rewritten in the same second is indexed again.
+    /// Old indexes don't have it, and their entries are read again once.
+    #[serde(default)]
+    pub modified_nanos: u32,
+}
+
+impl IndexEntry {
+    fn matches(&self, query: &str) -> bool {
+        self.title.to_lowercase().contains(query)
+            || self
+                .artists
+                .iter()
+                .any(|artist| artist.to_lowercase().contains(query))
+    }
+}
+
+/// An index of soundmap library.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LibraryIndex {
+    pub entries: Vec<IndexEntry>,
+}
+
+/// Build an index of every `*.smap` file in `dir`, and save it to `index_path`.
+///
+/// If an index already exists on `index_path`, entries whose file is not modified are reused.
+/// Others are read again from the archive.
+pub fn build_library_index(dir: &str, index_path: &str) -> Result<LibraryIndex, SmapError> {
+    let old_index = if Path::new(index_path).exists() {
+        load_library_index(index_path)?
+    } else {
+        LibraryIndex::default()
+    };
+
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().is_none_or(|ext| ext != "smap") {
+            continue;
+        }
+
+        let path_str = path.to_string_lossy().to_string();
+        let (modified, modified_nanos) = modified_time(&path)?;
+
+        // Reuse the entry if the file is not changed
+        if let Some(old) = old_index.entries.iter().find(|e| {
+            e.path == path_str && e.modified == modified && e.modified_nanos == modified_nanos
+        }) {
+            entries.push(old.clone());
+            continue;
+        }
+
+        let manifest = read_manifest_from_smap(&path_str)?;
+        entries.push(IndexEntry {
+            title: manifest.title,
+            artists: manifest.artists,
+            genre: manifest.genre,
+            path: path_str,
+            modified,
+            modified_nanos,
+        });
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let index = LibraryIndex { entries };
+    // Serializing the index can't fail
+    fs::write(index_path, serde_json::to_string(&index).unwrap())?;
+
+    Ok(index)
+}
+
+/// Load an index which saved by `build_library_index`.
+pub fn load_library_index(index_path: &str) -> Result<LibraryIndex, SmapError> {
+    let index = fs::read_to_string(index_path)?;
+    serde_json::from_str(&index).map_err(|source| SmapError::Json {
+        file: index_path.into(),
+        source,
+    })
+}
+
+/// Search entries by title or artists. It is case-insensitive substring search.
+pub fn search_index<'a>(index: &'a LibraryIndex, query: &str) -> Vec<&'a IndexEntry> {
+    let query = query.to_lowercase();
+    index.entries.iter().filter(|e| e.matches(&query)).collect()
+}
+
+/// Modified time of the file in seconds and nanoseconds since UNIX epoch.
+fn modified_time(path: &Path) -> io::Result<(u64, u32)> {
+    let modified = fs::metadata(path)?.modified()?;
+    Ok(modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| (d.as_secs(), d.subsec_nanos()))
+        .unwrap_or((0, 0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch_dir;
+    use crate::types::{Chart, Manifest, SoundMap};
+    use crate::{pack, save_smap_dir_overwrite};
+    use std::fs::File;
+    use std::time::Duration;
+
+    fn make_smap(dir: &str, name: &str, manifest: &Manifest) {
+        let charts: Vec<Chart> = Vec::new();
+        save_smap_dir_overwrite(name, dir, manifest, &SoundMap::new(), &charts).unwrap();
+        pack(dir, name, &format!("{name}.smap")).unwrap();
+    }
+
+    #[test]
+    fn build_and_search_index() {
+        let dir = scratch_dir("library_index");
+        let index_path = format!("{dir}/index.json");
+
+        let mut first = Manifest::new("Blue Sky", "Alice");
+        first.genre = "Pop".to_string();
+        make_smap(&dir, "first", &first);
+        make_smap(&dir, "second", &Manifest::new("Night Drive", "Bob"));
+
+        let index = build_library_index(&dir, &index_path).unwrap();
+        assert_eq!(index.entries.len(), 2);
+
+        // Reload from disk
+        let index = load_library_index(&index_path).unwrap();
+        let found = search_index(&index, "sky");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].title, "Blue Sky");
+        assert_eq!(found[0].genre, "Pop");
+
+        let found = search_index(&index, "BOB");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].title, "Night Drive");
+
+        assert!(search_index(&index, "nothing").is_empty());
+    }
+
+    #[test]
+    fn rebuild_index_on_modified() {
+        let dir = scratch_dir("library_index_modified");
+        let index_path = format!("{dir}/index.json");
+        let smap_path = format!("{dir}/song.smap");
+        let set_modified = |nanos: u32| {
+            let time = UNIX_EPOCH + Duration::new(1_000_000, nanos);
+            File::options()
+                .write(true)
+                .open(&smap_path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        };
+
+        make_smap(&dir, "song", &Manifest::new("Old Title", "Alice"));
+        set_modified(1);
+        let index = build_library_index(&dir, &index_path).unwrap();
+        assert_eq!(index.entries[0].title, "Old Title");
+
+        // Rewritten in the same second
+        make_smap(&dir, "song", &Manifest::new("New Title", "Alice"));
+        set_modified(2);
+        let index = build_library_index(&dir, &index_path).unwrap();
+        assert_eq!(index.entries[0].title, "New Title");
+        assert_eq!(index.entries[0].modified_nanos, 2);
+    }
+}
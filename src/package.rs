@@ -0,0 +1,261 @@
+//! Package processing
+//!
+//! Functions which handle a manifest, a soundmap and charts of a package together.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use crate::types::{Chart, Manifest, SoundMap};
+
+/// Sort everything in the package into the canonical order. Charts are sorted by name, and
+/// notes of each chart are kept in order.
+///
+/// See `Manifest::normalize` and `SoundMap::normalize`.
+pub fn normalize_package(manifest: &mut Manifest, soundmap: &mut SoundMap, charts: &mut [Chart]) {
+    manifest.normalize();
+    soundmap.normalize();
+    charts.sort_by(|a, b| a.name.cmp(&b.name));
+}
+
+/// Find sounds which would be orphaned if only `remaining_charts` are packed and
+/// `removed_charts` are removed, for slimming a package before packing a subset of charts.
+///
+/// A sound is kept if a note of the remaining charts references a soundmap note of the sound,
+/// or overrides its sound with it.
+/// Sounds which are already unused by soundmap notes and by overrides of every chart are not
+/// reported. It returns sound ids in the order of the manifest.
+pub fn unused_after_chart_removal(
+    manifest: &Manifest,
+    soundmap: &SoundMap,
+    remaining_charts: &[Chart],
+    removed_charts: &[Chart],
+) -> Vec<u16> {
+    let referenced: HashSet<u16> = remaining_charts
+        .iter()
+        .flat_map(|c| &c.content)
+        .filter_map(|n| n.sound.smap_note_id)
+        .collect();
+
+    let used: HashSet<u16> = soundmap
+        .notes
+        .iter()
+        .map(|n| n.sound_id)
+        .chain(
+            remaining_charts
+                .iter()
+                .chain(removed_charts)
+                .flat_map(|c| &c.content)
+                .filter_map(|n| n.sound.sound_override),
+        )
+        .collect();
+    let kept: HashSet<u16> = soundmap
+        .notes
+        .iter()
+        .filter(|n| referenced.contains(&n.id))
+        .map(|n| n.sound_id)
+        .chain(
+            remaining_charts
+                .iter()
+                .flat_map(|c| &c.content)
+                .filter_map(|n| n.sound.sound_override),
+        )
+        .collect();
+
+    manifest
+        .sounds
+        .iter()
+        .map(|s| s.id)
+        .filter(|id| used.contains(id) && !kept.contains(id))
+        .collect()
+}
+
+/// Get how many times each sound is used in average, as `note count / used sounds`.
+///
+/// It is `1.0` if each sound is used once, and higher if sounds are reused. Only sounds in the
+/// manifest are counted as used sounds. It returns `0.0` if there are no notes or no used
+/// sounds.
+pub fn package_sound_reuse(manifest: &Manifest, soundmap: &SoundMap) -> f64 {
+    let defined: HashSet<u16> = manifest.sounds.iter().map(|s| s.id).collect();
+    let used: HashSet<u16> = soundmap
+        .notes
+        .iter()
+        .map(|n| n.sound_id)
+        .filter(|id| defined.contains(id))
+        .collect();
+
+    if soundmap.notes.is_empty() || used.is_empty() {
+        return 0.0;
+    }
+    soundmap.notes.len() as f64 / used.len() as f64
+}
+
+/// Count notes of every chart by `note_type`. (ex. for finding packages which use too many
+/// flicks)
+///
+/// Fake notes are counted too. Note types which have no notes are not included.
+pub fn package_note_type_histogram(charts: &[Chart]) -> BTreeMap<u8, usize> {
+    let mut histogram = BTreeMap::new();
+    for note in charts.iter().flat_map(|c| &c.content) {
+        *histogram.entry(note.note_type).or_insert(0) += 1;
+    }
+    histogram
+}
+
+/// Get every lane which is used by notes of charts. (ex. for the lane count of a playfield)
+pub fn package_used_lanes(charts: &[Chart]) -> BTreeSet<u8> {
+    charts
+        .iter()
+        .flat_map(|c| &c.content)
+        .map(|n| n.lane)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::chart::{
+        NOTE_FLICK, NOTE_HOLD_END_FLICK, NOTE_HOLD_START, NOTE_NORMAL, PlayNote,
+    };
+    use crate::types::soundmap::{BeatPerBar, Bpm, Instrument};
+
+    #[test]
+    fn normalize_is_idempotent() {
+        let mut manifest = Manifest::new("Test", "Tester");
+        manifest.push_sound("kick.wav", 36);
+        manifest.push_sound("snare.wav", 38);
+        manifest.sounds.reverse();
+
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(0, 192, 1);
+        soundmap.insert_note(1, 0, 0);
+        soundmap.notes.reverse();
+        soundmap.set_note_track(1, "Snare", Instrument::Snare);
+        soundmap.set_note_track(0, "Kick", Instrument::Kick);
+        soundmap.bpm.push(Bpm::new(150.0, 768));
+        soundmap.bpm.push(Bpm::new(120.0, 384));
+        soundmap.bpm.push(Bpm::new(140.0, 768));
+        soundmap.beat_per_bar.push(BeatPerBar::new(3, 768));
+        soundmap.beat_per_bar.push(BeatPerBar::new(4, 768));
+        // A mid-bar re-declaration restarts the bar, so it is kept
+        soundmap.beat_per_bar.push(BeatPerBar::new(4, 960));
+
+        let mut charts = vec![Chart::new("Hard", "Tester"), Chart::new("Easy", "Tester")];
+
+        normalize_package(&mut manifest, &mut soundmap, &mut charts);
+        assert_eq!(manifest.sounds[0].id, 0);
+        assert_eq!(soundmap.notes[0].id, 0);
+        assert_eq!(soundmap.track_tags[0].id, 0);
+        let bpm: Vec<(u32, f64)> = soundmap.bpm.iter().map(|b| (b.time, b.value)).collect();
+        assert_eq!(bpm, vec![(0, 120.0), (768, 140.0)]);
+        let meters: Vec<(u32, u8)> = soundmap
+            .beat_per_bar
+            .iter()
+            .map(|b| (b.time, b.value))
+            .collect();
+        assert_eq!(meters, vec![(0, 4), (768, 4), (960, 4)]);
+        assert_eq!(charts[0].name, "Easy");
+
+        let normalized = (
+            serde_json::to_string(&manifest).unwrap(),
+            serde_json::to_string(&soundmap).unwrap(),
+            serde_json::to_string(&charts).unwrap(),
+        );
+        normalize_package(&mut manifest, &mut soundmap, &mut charts);
+        assert_eq!(
+            normalized,
+            (
+                serde_json::to_string(&manifest).unwrap(),
+                serde_json::to_string(&soundmap).unwrap(),
+                serde_json::to_string(&charts).unwrap(),
+            )
+        );
+    }
+
+    #[test]
+    fn unused_after_removal() {
+        let mut manifest = Manifest::new("Test", "Tester");
+        manifest.push_sound("kick.wav", 36);
+        manifest.push_sound("snare.wav", 38);
+        manifest.push_sound("unused.wav", 42);
+        let soundmap = SoundMap::from_notes([(0, 0, 0), (1, 192, 0)]);
+
+        let mut easy = Chart::new("Easy", "Tester");
+        easy.insert_note(0, 0);
+        let mut hard = Chart::new("Hard", "Tester");
+        hard.insert_note(0, 0);
+        hard.insert_note(1, 1);
+
+        let charts = [easy.clone(), hard.clone()];
+        assert!(unused_after_chart_removal(&manifest, &soundmap, &charts, &[]).is_empty());
+        // Snare is only used by the hard chart
+        assert_eq!(
+            unused_after_chart_removal(&manifest, &soundmap, &[easy], &[hard.clone()]),
+            vec![1]
+        );
+
+        // The removed chart uses the unused sound only as an override
+        let mut extra = Chart::new("Extra", "Tester");
+        extra.insert_note(0, 0);
+        extra.content[0].sound.sound_override = Some(2);
+        assert_eq!(
+            unused_after_chart_removal(&manifest, &soundmap, &[hard.clone()], &[extra.clone()]),
+            vec![2]
+        );
+        assert!(unused_after_chart_removal(&manifest, &soundmap, &[hard, extra], &[]).is_empty());
+    }
+
+    #[test]
+    fn sound_reuse() {
+        let mut manifest = Manifest::new("Test", "Tester");
+        manifest.push_sound("kick.wav", 36);
+        manifest.push_sound("snare.wav", 38);
+        manifest.push_sound("unused.wav", 42);
+
+        let soundmap = SoundMap::from_notes([(0, 0, 0), (1, 192, 0), (0, 384, 0), (1, 576, 0)]);
+        assert_eq!(package_sound_reuse(&manifest, &soundmap), 2.0);
+        assert_eq!(package_sound_reuse(&manifest, &SoundMap::new()), 0.0);
+    }
+
+    #[test]
+    fn note_type_histogram() {
+        let mut easy = Chart::new("Easy", "Tester");
+        easy.insert_note(0, 0);
+        easy.insert_note(1, 1);
+        easy.content
+            .push(PlayNote::new().with_lane(2).with_type(NOTE_FLICK));
+
+        let mut hard = Chart::new("Hard", "Tester");
+        hard.content
+            .push(PlayNote::new().with_lane(0).with_type(NOTE_HOLD_START));
+        hard.content
+            .push(PlayNote::new().with_lane(0).with_type(NOTE_HOLD_END_FLICK));
+        hard.content
+            .push(PlayNote::new().with_lane(1).with_type(NOTE_FLICK).fake());
+
+        let histogram = package_note_type_histogram(&[easy, hard]);
+        assert_eq!(
+            histogram.into_iter().collect::<Vec<_>>(),
+            vec![
+                (NOTE_NORMAL, 2),
+                (NOTE_FLICK, 2),
+                (NOTE_HOLD_START, 1),
+                (NOTE_HOLD_END_FLICK, 1)
+            ]
+        );
+        assert!(package_note_type_histogram(&[]).is_empty());
+    }
+
+    #[test]
+    fn used_lanes() {
+        let mut left = Chart::new("Left", "Tester");
+        left.insert_note(0, 0);
+        left.insert_note(1, 1);
+        left.insert_note(1, 2);
+        let mut right = Chart::new("Right", "Tester");
+        right.insert_note(3, 0);
+        right.insert_silent_note(2, 192);
+
+        let lanes = package_used_lanes(&[left, right]);
+        assert_eq!(lanes, BTreeSet::from([0, 1, 2, 3]));
+        assert!(package_used_lanes(&[]).is_empty());
+    }
+}
@@ -1,4 +1,193 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::ops::RangeInclusive;
+
+use crate::error::SmapError;
+use crate::hash::Fnv1a;
+use crate::types::manifest::Manifest;
+use crate::types::soundmap::SoundMap;
+
+/// A chart type which is known by this library.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChartTypeInfo {
+    /// The canonical name of the type
+    pub name: &'static str,
+
+    /// A count of lanes. `None` if it is not fixed.
+    pub lanes: Option<u8>,
+
+    /// The maximum difficulty level. `None` if it is not limited. (See `Chart::validate_level`)
+    pub max_level: Option<u8>,
+}
+
+/// A list of known chart types.
+///
+/// Other spellings (ex. `4k`, `4 Keys`, `Four Keys`) are canonicalized to these names.
+pub const KNOWN_CHART_TYPES: &[ChartTypeInfo] = &[
+    ChartTypeInfo {
+        name: "Plain",
+        lanes: None,
+        max_level: None,
+    },
+    ChartTypeInfo {
+        name: "4K",
+        lanes: Some(4),
+        max_level: Some(20),
+    },
+    ChartTypeInfo {
+        name: "5K",
+        lanes: Some(5),
+        max_level: Some(20),
+    },
+    ChartTypeInfo {
+        name: "6K",
+        lanes: Some(6),
+        max_level: Some(20),
+    },
+    ChartTypeInfo {
+        name: "7K",
+        lanes: Some(7),
+        max_level: Some(20),
+    },
+    ChartTypeInfo {
+        name: "8K",
+        lanes: Some(8),
+        max_level: Some(20),
+    },
+    ChartTypeInfo {
+        name: "9K",
+        lanes: Some(9),
+        max_level: Some(20),
+    },
+    ChartTypeInfo {
+        name: "10K",
+        lanes: Some(10),
+        max_level: Some(20),
+    },
+];
+
+/// An expected note density for difficulty levels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DensityRange {
+    /// Difficulty levels which use this range
+    pub levels: RangeInclusive<u8>,
+
+    /// Expected judged notes per second
+    pub notes_per_second: RangeInclusive<f64>,
+}
+
+/// A default table of `DensityRange`. (See `Chart::plausibility_check`)
+///
+/// Ranges are wide on purpose, so only charts which wildly mismatch the level are warned.
+pub const DEFAULT_DENSITY_RANGES: &[DensityRange] = &[
+    DensityRange {
+        levels: 1..=3,
+        notes_per_second: 0.0..=4.0,
+    },
+    DensityRange {
+        levels: 4..=6,
+        notes_per_second: 0.5..=8.0,
+    },
+    DensityRange {
+        levels: 7..=9,
+        notes_per_second: 1.0..=14.0,
+    },
+    DensityRange {
+        levels: 10..=12,
+        notes_per_second: 2.0..=20.0,
+    },
+    DensityRange {
+        levels: 13..=u8::MAX,
+        notes_per_second: 3.0..=f64::MAX,
+    },
+];
+
+/// A warning which found by `Chart::plausibility_check`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlausibilityWarning {
+    /// Notes are too dense for the level.
+    TooDense {
+        level: u8,
+        notes_per_second: f64,
+        expected_max: f64,
+    },
+
+    /// Notes are too sparse for the level.
+    TooSparse {
+        level: u8,
+        notes_per_second: f64,
+        expected_min: f64,
+    },
+}
+
+/// Weights of `Chart::estimate_difficulty_with`.
+///
+/// Each metric is multiplied by its weight, and the sum is the estimated level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DifficultyWeights {
+    /// A weight of average judged notes per second
+    pub notes_per_second: f64,
+
+    /// A weight of the most judged notes in 1 second
+    pub peak_notes_per_second: f64,
+
+    /// A weight of the ratio of jacks (`0.0` ~ `1.0`)
+    pub jack_ratio: f64,
+
+    /// A weight of the count of used lanes
+    pub lanes: f64,
+
+    /// A note is a jack if the previous note on the same lane is within this seconds.
+    pub jack_seconds: f64,
+}
+
+impl Default for DifficultyWeights {
+    fn default() -> Self {
+        Self {
+            notes_per_second: 0.8,
+            peak_notes_per_second: 0.5,
+            jack_ratio: 4.0,
+            lanes: 0.25,
+            jack_seconds: 0.25,
+        }
+    }
+}
+
+/// Find the known chart type which matches `chart_type`.
+pub fn find_chart_type(chart_type: &str) -> Option<&'static ChartTypeInfo> {
+    // Ignore case, spaces and separators
+    let normalized: String = chart_type
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-' && *c != '_')
+        .flat_map(char::to_lowercase)
+        .collect();
+
+    if let Some(info) = KNOWN_CHART_TYPES
+        .iter()
+        .find(|info| info.name.to_lowercase() == normalized)
+    {
+        return Some(info);
+    }
+
+    // Keys (ex. `4key`, `fourkeys`)
+    let count = ["keys", "key", "k"]
+        .iter()
+        .find_map(|suffix| normalized.strip_suffix(suffix))?;
+    let lanes = match count {
+        "four" => 4,
+        "five" => 5,
+        "six" => 6,
+        "seven" => 7,
+        "eight" => 8,
+        "nine" => 9,
+        "ten" => 10,
+        number => number.parse().ok()?,
+    };
+    KNOWN_CHART_TYPES
+        .iter()
+        .find(|info| info.name != "Plain" && info.lanes == Some(lanes))
+}
 
 /// A sound definition for the chart.
 ///
@@ -7,24 +196,84 @@ use serde::{Deserialize, Serialize};
 ///
 /// If `smap_note_id` is `Some(u16)`, it means that the sound is associated with a specific note. and `time` is unused. but it recommends to be same as the note of soundmap defined.
 /// If `smap_note_id` is `None`, it means that the sound is not associated with any specific note. instead `time` is used for specific note timing.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// If `sound_override` is `Some(u16)`, the `Sound` of the manifest with the id is played instead
+/// of the sound of the soundmap note. (ex. keysound swaps)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct NoteSound {
     pub smap_note_id: Option<u16>,
     pub time: u32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sound_override: Option<u16>,
 }
 
-impl Default for NoteSound {
-    fn default() -> Self {
-        Self {
-            smap_note_id: None,
-            time: 0,
+impl NoteSound {
+    /// Get the time of the sound in ticks.
+    ///
+    /// If it is associated with a note of the soundmap, the time of the note is used.
+    /// It returns `None` if the note doesn't exist in the soundmap.
+    pub fn resolve_time(&self, soundmap: &SoundMap) -> Option<u32> {
+        match self.smap_note_id {
+            Some(id) => soundmap.get_note(id).map(|n| n.time),
+            None => Some(self.time),
+        }
+    }
+
+    /// Get the id of the `Sound` to play.
+    ///
+    /// `sound_override` is used first, and then the sound of the soundmap note. It returns `None`
+    /// for silent notes, or if the note doesn't exist in the soundmap.
+    pub fn resolve_sound(&self, soundmap: &SoundMap) -> Option<u16> {
+        if self.sound_override.is_some() {
+            return self.sound_override;
         }
+        soundmap.get_note(self.smap_note_id?).map(|n| n.sound_id)
+    }
+}
+
+// Note types of `PlayNote.note_type`
+pub const NOTE_NORMAL: u8 = 0;
+pub const NOTE_FLICK: u8 = 1;
+pub const NOTE_HOLD_START: u8 = 2;
+pub const NOTE_HOLD_END: u8 = 3;
+pub const NOTE_HOLD_END_FLICK: u8 = 4;
+pub const NOTE_SLIDE_START: u8 = 5;
+pub const NOTE_SLIDE_END: u8 = 6;
+pub const NOTE_SLIDE_END_FLICK: u8 = 7;
+
+/// A scroll velocity (SV) change of the chart.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ScrollVelocity {
+    /// Same as `Note.time` of the soundmap.
+    pub time: u32,
+
+    /// A multiplier of scroll speed. `1.0` is normal speed.
+    pub multiplier: f32,
+}
+
+impl ScrollVelocity {
+    pub fn new(time: u32, multiplier: f32) -> Self {
+        Self { time, multiplier }
     }
 }
 
+/// How to repair notes which reference missing soundmap notes. (See `Chart::repair_dangling`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DanglingStrategy {
+    /// Remove the note from the chart.
+    DropNote,
+
+    /// Make the note silent, on the time.
+    ConvertToExplicitTime(u32),
+
+    /// Reference the soundmap note which has the nearest id. (The lower one on a tie)
+    NearestNote,
+}
+
 /// A note definition for the chart.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PlayNote {
     /// A note definition of sound.
@@ -55,17 +304,14 @@ pub struct PlayNote {
     /// A note's lane on the chart
     /// It depends on the chart type
     pub lane: u8,
-}
 
-impl Default for PlayNote {
-    fn default() -> Self {
-        Self {
-            sound: NoteSound::default(),
-            note_type: 0,
-            group: 0,
-            lane: 0,
-        }
-    }
+    /// A fake note is decorative, and it isn't judged.
+    ///
+    /// Counts and density of `Chart` (`note_count`, `densest_measure` and `density_series`)
+    /// include fake notes only if `include_fakes` is set. Other stats are about judgment, so
+    /// they never include fake notes. (ex. `estimate_difficulty`)
+    #[serde(default)]
+    pub fake: bool,
 }
 
 impl PlayNote {
@@ -74,18 +320,20 @@ impl PlayNote {
     }
 
     pub fn with_sound(mut self, smap_note_id: u16) -> Self {
-        self.sound = NoteSound {
-            smap_note_id: Some(smap_note_id),
-            time: 0,
-        };
+        self.sound.smap_note_id = Some(smap_note_id);
+        self.sound.time = 0;
         self
     }
 
     pub fn with_time(mut self, time: u32) -> Self {
-        self.sound = NoteSound {
-            smap_note_id: None,
-            time,
-        };
+        self.sound.smap_note_id = None;
+        self.sound.time = time;
+        self
+    }
+
+    /// Play the `Sound` of `sound_id` instead of the sound of the soundmap note.
+    pub fn with_sound_override(mut self, sound_id: u16) -> Self {
+        self.sound.sound_override = Some(sound_id);
         self
     }
 
@@ -103,6 +351,11 @@ impl PlayNote {
         self.lane = note_lane;
         self
     }
+
+    pub fn fake(mut self) -> Self {
+        self.fake = true;
+        self
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -112,8 +365,18 @@ pub struct Chart {
     pub name: String,
 
     /// A type of chart
+    ///
+    /// It should be one of `KNOWN_CHART_TYPES`, unless `custom_type` is set.
     pub chart_type: String,
 
+    /// Whether `chart_type` is a custom type which is not in `KNOWN_CHART_TYPES`.
+    #[serde(default)]
+    pub custom_type: bool,
+
+    /// Whether `difficulty_level` may be higher than `max_level` of the type.
+    #[serde(default)]
+    pub ignore_max_level: bool,
+
     /// A author of chart
     pub author: String,
 
@@ -130,6 +393,22 @@ pub struct Chart {
 
     /// Variation (In BMS, called 'sabun(差分)') or not
     pub variation: bool,
+
+    /// A name of base chart
+    ///
+    /// If it is set, notes of the base chart are also a part of this chart. (See `resolve_base`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+
+    /// A start of the preview in milliseconds, for this chart only.
+    ///
+    /// If it is set, it overrides `Manifest.preview_start_ms`. (See `effective_preview`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview_start_ms: Option<u32>,
+
+    /// A list of scroll velocity changes, sorted by time.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scroll_velocities: Vec<ScrollVelocity>,
 }
 
 impl Default for Chart {
@@ -138,20 +417,75 @@ impl Default for Chart {
             name: "Chart".to_string(),
             author: "Unknown".to_string(),
             chart_type: "Plain".to_string(),
+            custom_type: false,
+            ignore_max_level: false,
             difficulty_type: 0,
             difficulty_level: 1,
             content: vec![],
             variation: false,
+            base: None,
+            preview_start_ms: None,
+            scroll_velocities: Vec::new(),
         }
     }
 }
 
 impl Chart {
     pub fn new(name: &str, author: &str) -> Self {
-        let mut chart = Self::default();
-        chart.name = name.to_string();
-        chart.author = author.to_string();
-        chart
+        Self {
+            name: name.to_string(),
+            author: author.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Make a chart which has all notes of the soundmap, for auditioning. Each track is a lane,
+    /// in order of track ids. Lanes wrap to `0` if there are more than 255 tracks.
+    pub fn from_soundmap_by_track(soundmap: &SoundMap) -> Chart {
+        Self::from_soundmap_by_track_with(soundmap, u8::MAX, true).unwrap()
+    }
+
+    /// Same as `from_soundmap_by_track`, but with `max_lanes`. If there are more tracks than
+    /// `max_lanes`, lanes wrap if `wrap` is set, or it returns an error if not.
+    ///
+    /// The chart type is a known type which has the count of lanes. (ex. `4K`)
+    pub fn from_soundmap_by_track_with(
+        soundmap: &SoundMap,
+        max_lanes: u8,
+        wrap: bool,
+    ) -> Result<Chart, SmapError> {
+        if max_lanes == 0 {
+            return Err(SmapError::InvalidChart("Max lanes is 0".to_string()));
+        }
+
+        let mut tracks: Vec<u16> = soundmap.notes.iter().map(|n| n.track).collect();
+        tracks.sort_unstable();
+        tracks.dedup();
+        if tracks.len() > usize::from(max_lanes) && !wrap {
+            return Err(SmapError::InvalidChart(format!(
+                "{} tracks can't be mapped to {max_lanes} lanes",
+                tracks.len()
+            )));
+        }
+
+        let mut notes: Vec<_> = soundmap.notes.iter().collect();
+        notes.sort_by_key(|n| (n.time, n.id));
+
+        let mut chart = Chart::new("Tracks", "Unknown");
+        for note in notes {
+            // Tracks are sorted and deduplicated, so it doesn't fail
+            let index = tracks.binary_search(&note.track).unwrap();
+            chart.insert_note((index % usize::from(max_lanes)) as u8, note.id);
+        }
+
+        let lanes = tracks.len().min(usize::from(max_lanes));
+        if let Some(info) = KNOWN_CHART_TYPES
+            .iter()
+            .find(|info| info.lanes.is_some_and(|l| usize::from(l) == lanes))
+        {
+            chart.chart_type = info.name.to_string();
+        }
+        Ok(chart)
     }
 
     pub fn with_chart_type(mut self, chart_type: &str) -> Self {
@@ -159,6 +493,13 @@ impl Chart {
         self
     }
 
+    /// Set a custom chart type, which is not in `KNOWN_CHART_TYPES`.
+    pub fn with_custom_type(mut self, chart_type: &str) -> Self {
+        self.chart_type = chart_type.to_string();
+        self.custom_type = true;
+        self
+    }
+
     pub fn with_difficulty_type(mut self, diff_type: u8) -> Self {
         self.difficulty_type = diff_type;
         self
@@ -169,11 +510,63 @@ impl Chart {
         self
     }
 
+    /// Allow `difficulty_level` higher than `max_level` of the type. (ex. joke charts)
+    pub fn ignore_max_level(mut self) -> Self {
+        self.ignore_max_level = true;
+        self
+    }
+
     pub fn variation(mut self) -> Self {
         self.variation = true;
         self
     }
 
+    pub fn with_base(mut self, base: &str) -> Self {
+        self.base = Some(base.to_string());
+        self
+    }
+
+    pub fn with_preview_start(mut self, preview_start_ms: u32) -> Self {
+        self.preview_start_ms = Some(preview_start_ms);
+        self
+    }
+
+    /// Get the start of the preview in milliseconds. The chart's one is used first, and the
+    /// manifest's one is used if it isn't set.
+    pub fn effective_preview(&self, manifest: &Manifest) -> Option<u32> {
+        self.preview_start_ms.or(manifest.preview_start_ms)
+    }
+
+    /// Make a chart which merges notes of base charts. The result has no `base`.
+    ///
+    /// Base charts are found by name from `charts`. Notes of the base come first, and notes of
+    /// this chart are added after them.
+    pub fn resolve_base(&self, charts: &[Chart]) -> Result<Chart, SmapError> {
+        let mut content = self.content.clone();
+        let mut visited = vec![self.name.as_str()];
+        let mut base_name = self.base.as_deref();
+
+        while let Some(name) = base_name {
+            if visited.contains(&name) {
+                return Err(SmapError::ChartCycle(name.to_string()));
+            }
+            let base = charts
+                .iter()
+                .find(|c| c.name == name)
+                .ok_or_else(|| SmapError::ChartNotFound(name.to_string()))?;
+
+            content.splice(0..0, base.content.iter().cloned());
+            visited.push(name);
+            base_name = base.base.as_deref();
+        }
+
+        Ok(Chart {
+            content,
+            base: None,
+            ..self.clone()
+        })
+    }
+
     pub fn insert_note(&mut self, lane: u8, smap_note_id: u16) {
         let note = PlayNote::new().with_lane(lane).with_sound(smap_note_id);
         self.content.push(note);
@@ -183,4 +576,1228 @@ impl Chart {
         let note = PlayNote::new().with_lane(lane).with_time(time);
         self.content.push(note);
     }
+
+    /// Add a scroll velocity change. It is inserted in order of time.
+    pub fn add_sv(&mut self, time: u32, multiplier: f32) {
+        let index = self.scroll_velocities.partition_point(|sv| sv.time <= time);
+        self.scroll_velocities
+            .insert(index, ScrollVelocity::new(time, multiplier));
+    }
+
+    /// Copy times of soundmap notes to notes which reference them, and remove the references.
+    /// It is useful for exporters which don't understand `smap_note_id`.
+    ///
+    /// If a note references a missing soundmap note, nothing changes.
+    pub fn materialize_times(&mut self, soundmap: &SoundMap) -> Result<(), SmapError> {
+        let times = self
+            .content
+            .iter()
+            .enumerate()
+            .map(|(index, note)| {
+                note.sound.resolve_time(soundmap).ok_or_else(|| {
+                    SmapError::InvalidChart(format!(
+                        "Note {index} references missing soundmap note {:?}",
+                        note.sound.smap_note_id
+                    ))
+                })
+            })
+            .collect::<Result<Vec<u32>, SmapError>>()?;
+
+        for (note, time) in self.content.iter_mut().zip(times) {
+            note.sound.smap_note_id = None;
+            note.sound.time = time;
+        }
+        Ok(())
+    }
+
+    /// Check every `smap_note_id` of notes is in the soundmap.
+    ///
+    /// It fails with distinct missing ids, in the order of notes. Notes with explicit time are
+    /// not checked.
+    pub fn validate_against(&self, soundmap: &SoundMap) -> Result<(), Vec<u16>> {
+        let mut missing = Vec::new();
+        for id in self.content.iter().filter_map(|n| n.sound.smap_note_id) {
+            if soundmap.get_note(id).is_none() && !missing.contains(&id) {
+                missing.push(id);
+            }
+        }
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(missing)
+        }
+    }
+
+    /// Repair notes which reference missing soundmap notes with `strategy`. It returns the count
+    /// of repaired notes.
+    ///
+    /// With `NearestNote`, notes are dropped if the soundmap has no notes.
+    pub fn repair_dangling(&mut self, soundmap: &SoundMap, strategy: DanglingStrategy) -> usize {
+        let is_dangling = |note: &PlayNote| note.sound.resolve_time(soundmap).is_none();
+        let count = self.content.iter().filter(|n| is_dangling(n)).count();
+
+        match strategy {
+            DanglingStrategy::DropNote => self.content.retain(|n| !is_dangling(n)),
+            DanglingStrategy::ConvertToExplicitTime(time) => {
+                for note in self.content.iter_mut().filter(|n| is_dangling(n)) {
+                    note.sound.smap_note_id = None;
+                    note.sound.time = time;
+                }
+            }
+            DanglingStrategy::NearestNote => {
+                let mut ids: Vec<u16> = soundmap.notes.iter().map(|n| n.id).collect();
+                ids.sort_unstable();
+                if ids.is_empty() {
+                    self.content.retain(|n| !is_dangling(n));
+                }
+                for note in self.content.iter_mut().filter(|n| is_dangling(n)) {
+                    let id = note.sound.smap_note_id.unwrap();
+                    let nearest = ids
+                        .iter()
+                        .min_by_key(|candidate| candidate.abs_diff(id))
+                        .copied();
+                    note.sound.smap_note_id = nearest;
+                }
+            }
+        }
+        count
+    }
+
+    /// Get the canonical name of the chart type.
+    ///
+    /// If the type is custom or unknown, it returns `chart_type` as it is.
+    pub fn canonical_type(&self) -> &str {
+        if self.custom_type {
+            return &self.chart_type;
+        }
+        match find_chart_type(&self.chart_type) {
+            Some(info) => info.name,
+            None => &self.chart_type,
+        }
+    }
+
+    /// Whether the chart type is unknown and not marked as custom.
+    pub fn has_unknown_type(&self) -> bool {
+        !self.custom_type && find_chart_type(&self.chart_type).is_none()
+    }
+
+    /// Check `difficulty_level` is not higher than `max_level` of the chart type.
+    ///
+    /// Custom or unknown types and charts with `ignore_max_level` are not checked.
+    pub fn validate_level(&self) -> Result<(), SmapError> {
+        if self.custom_type || self.ignore_max_level {
+            return Ok(());
+        }
+        let Some(info) = find_chart_type(&self.chart_type) else {
+            return Ok(());
+        };
+        match info.max_level {
+            Some(max) if self.difficulty_level > max => Err(SmapError::LevelOutOfRange {
+                chart: self.name.clone(),
+                level: self.difficulty_level,
+                max,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Get notes which are judged. (Not fake)
+    pub fn judged_notes(&self) -> impl Iterator<Item = &PlayNote> {
+        self.content.iter().filter(|n| !n.fake)
+    }
+
+    /// Get all notes if `include_fakes` is set, or only judged notes.
+    fn notes(&self, include_fakes: bool) -> impl Iterator<Item = &PlayNote> {
+        self.content
+            .iter()
+            .filter(move |n| include_fakes || !n.fake)
+    }
+
+    /// Count notes of the chart. Fake notes are counted only if `include_fakes` is set.
+    pub fn note_count(&self, include_fakes: bool) -> usize {
+        self.notes(include_fakes).count()
+    }
+
+    /// Get judgment windows of each note.
+    ///
+    /// It returns an entry for each note in `content`, in the same order. An entry has the
+    /// center time of the note in milliseconds, and `start, end` bounds in milliseconds for
+    /// each window. (ex. `[start of windows[0], end of windows[0], start of windows[1], ...]`)
+    /// Each of `windows` is a half-width in milliseconds. (ex. `50.0` means ±50ms)
+    ///
+    /// Fake notes are not judged, so they are `None`. Notes which reference a missing soundmap
+    /// note are also `None`.
+    pub fn judgment_windows(
+        &self,
+        soundmap: &SoundMap,
+        windows: &[f64],
+    ) -> Vec<Option<(f64, Vec<f64>)>> {
+        self.content
+            .iter()
+            .map(|note| {
+                if note.fake {
+                    return None;
+                }
+                let time = note.sound.resolve_time(soundmap)?;
+                let center = soundmap.tick_to_seconds(time) * 1000.0;
+                let bounds = windows
+                    .iter()
+                    .flat_map(|window| [center - window, center + window])
+                    .collect();
+                Some((center, bounds))
+            })
+            .collect()
+    }
+
+    /// Find the measure which has the most notes. (See `SoundMap::measure_boundaries`) Fake
+    /// notes are counted only if `include_fakes` is set.
+    ///
+    /// It returns `(measure index, note count)`. If some measures have same count, the earliest
+    /// one is returned. It returns `None` if there are no notes.
+    pub fn densest_measure(
+        &self,
+        soundmap: &SoundMap,
+        include_fakes: bool,
+    ) -> Option<(u32, usize)> {
+        let times: Vec<u32> = self
+            .notes(include_fakes)
+            .filter_map(|n| n.sound.resolve_time(soundmap))
+            .collect();
+        let end = times.iter().copied().max()?;
+        let boundaries = soundmap.measure_boundaries_until(end);
+
+        let mut counts = vec![0; boundaries.len()];
+        for time in times {
+            let measure = boundaries.partition_point(|b| *b <= time) - 1;
+            counts[measure] += 1;
+        }
+
+        let mut densest = (0, counts[0]);
+        for (measure, count) in counts.into_iter().enumerate() {
+            if count > densest.1 {
+                densest = (measure, count);
+            }
+        }
+        Some((densest.0 as u32, densest.1))
+    }
+
+    /// Compare note density with `difficulty_level`, using `DEFAULT_DENSITY_RANGES`.
+    pub fn plausibility_check(&self, soundmap: &SoundMap) -> Vec<PlausibilityWarning> {
+        self.plausibility_check_with(soundmap, DEFAULT_DENSITY_RANGES)
+    }
+
+    /// Same as `plausibility_check`, but with custom `ranges`.
+    ///
+    /// Density is judged notes per second, from the first judged note to the last one. (At least
+    /// 1 second) Charts with less than 2 notes, or with a level which isn't in `ranges` are not
+    /// checked.
+    pub fn plausibility_check_with(
+        &self,
+        soundmap: &SoundMap,
+        ranges: &[DensityRange],
+    ) -> Vec<PlausibilityWarning> {
+        let level = self.difficulty_level;
+        let Some(range) = ranges.iter().find(|r| r.levels.contains(&level)) else {
+            return Vec::new();
+        };
+
+        let times: Vec<u32> = self
+            .judged_notes()
+            .filter_map(|n| n.sound.resolve_time(soundmap))
+            .collect();
+        let (Some(first), Some(last)) = (times.iter().min(), times.iter().max()) else {
+            return Vec::new();
+        };
+        if times.len() < 2 {
+            return Vec::new();
+        }
+
+        let duration = soundmap.tick_to_seconds(*last) - soundmap.tick_to_seconds(*first);
+        let notes_per_second = times.len() as f64 / duration.max(1.0);
+
+        let mut warnings = Vec::new();
+        if notes_per_second > *range.notes_per_second.end() {
+            warnings.push(PlausibilityWarning::TooDense {
+                level,
+                notes_per_second,
+                expected_max: *range.notes_per_second.end(),
+            });
+        } else if notes_per_second < *range.notes_per_second.start() {
+            warnings.push(PlausibilityWarning::TooSparse {
+                level,
+                notes_per_second,
+                expected_min: *range.notes_per_second.start(),
+            });
+        }
+        warnings
+    }
+
+    /// Estimate a difficulty level from note timing, with default `DifficultyWeights`.
+    ///
+    /// It is a rough heuristic for auto-tagging, which is about 1~20. Don't use it instead of
+    /// `difficulty_level` written by charters.
+    pub fn estimate_difficulty(&self, soundmap: &SoundMap) -> u8 {
+        self.estimate_difficulty_with(soundmap, &DifficultyWeights::default())
+    }
+
+    /// Same as `estimate_difficulty`, but with custom `weights`.
+    ///
+    /// Metrics are average notes per second (like `plausibility_check`), the most notes in 1
+    /// second, the ratio of jacks and the count of used lanes. Only judged notes are counted.
+    /// The result is rounded and clamped to 1~20. Charts without notes get 1.
+    pub fn estimate_difficulty_with(&self, soundmap: &SoundMap, weights: &DifficultyWeights) -> u8 {
+        let mut notes: Vec<(f64, u8)> = self
+            .judged_notes()
+            .filter_map(|n| {
+                let time = n.sound.resolve_time(soundmap)?;
+                Some((soundmap.tick_to_seconds(time), n.lane))
+            })
+            .collect();
+        if notes.is_empty() {
+            return 1;
+        }
+        notes.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let duration = notes[notes.len() - 1].0 - notes[0].0;
+        let notes_per_second = notes.len() as f64 / duration.max(1.0);
+
+        let mut peak = 0;
+        let mut window_start = 0;
+        for (index, (seconds, _)) in notes.iter().enumerate() {
+            while notes[window_start].0 <= seconds - 1.0 {
+                window_start += 1;
+            }
+            peak = peak.max(index - window_start + 1);
+        }
+
+        let mut last_on_lane: HashMap<u8, f64> = HashMap::new();
+        let mut jacks = 0;
+        for (seconds, lane) in &notes {
+            if let Some(last) = last_on_lane.insert(*lane, *seconds)
+                && seconds - last <= weights.jack_seconds
+            {
+                jacks += 1;
+            }
+        }
+        let jack_ratio = jacks as f64 / notes.len() as f64;
+
+        let score = notes_per_second * weights.notes_per_second
+            + peak as f64 * weights.peak_notes_per_second
+            + jack_ratio * weights.jack_ratio
+            + last_on_lane.len() as f64 * weights.lanes;
+        score.round().clamp(1.0, 20.0) as u8
+    }
+
+    /// Pair starts and ends of hold and slide notes, in order of time. (See `pair_long_notes`)
+    ///
+    /// Notes are ordered by the resolved time, and by lane if they are on the same time. Notes
+    /// which reference a missing soundmap note are the last.
+    pub(crate) fn long_note_pairs(
+        &self,
+        soundmap: &SoundMap,
+    ) -> Result<Vec<(usize, usize)>, SmapError> {
+        let mut order: Vec<usize> = (0..self.content.len()).collect();
+        order.sort_by_key(|index| {
+            let note = &self.content[*index];
+            let time = note.sound.resolve_time(soundmap);
+            (time.is_none(), time, note.lane)
+        });
+        self.pair_long_notes(order)
+    }
+
+    /// Pair starts and ends of hold and slide notes, in `order` of indices of `content`.
+    ///
+    /// A hold note is paired with the next hold end on the same lane. A slide note is paired
+    /// with the next slide end in the same group, or on the same lane if the group is `0`.
+    /// It returns indices of `(start, end)`, sorted by the start.
+    fn pair_long_notes(
+        &self,
+        order: impl IntoIterator<Item = usize>,
+    ) -> Result<Vec<(usize, usize)>, SmapError> {
+        let mut open: Vec<((bool, u8, u8), usize)> = Vec::new();
+        let mut pairs = Vec::new();
+
+        for index in order {
+            let note = &self.content[index];
+            let (is_start, is_slide) = match note.note_type {
+                NOTE_HOLD_START => (true, false),
+                NOTE_HOLD_END | NOTE_HOLD_END_FLICK => (false, false),
+                NOTE_SLIDE_START => (true, true),
+                NOTE_SLIDE_END | NOTE_SLIDE_END_FLICK => (false, true),
+                _ => continue,
+            };
+            let key = if is_slide && note.group != 0 {
+                (true, 0, note.group)
+            } else {
+                (is_slide, note.lane, 0)
+            };
+            let kind = if is_slide { "Slide" } else { "Hold" };
+
+            let opened = open.iter().position(|(k, _)| *k == key);
+            match (is_start, opened) {
+                (true, None) => open.push((key, index)),
+                (true, Some(_)) => {
+                    return Err(SmapError::InvalidChart(format!(
+                        "{kind} note {index} starts before the previous one ends"
+                    )));
+                }
+                (false, Some(position)) => pairs.push((open.remove(position).1, index)),
+                (false, None) => {
+                    return Err(SmapError::InvalidChart(format!(
+                        "{kind} note {index} ends without a start"
+                    )));
+                }
+            }
+        }
+
+        if let Some((_, index)) = open.first() {
+            return Err(SmapError::InvalidChart(format!(
+                "Note {index} starts but never ends"
+            )));
+        }
+
+        pairs.sort_unstable();
+        Ok(pairs)
+    }
+
+    /// Reassign `group` of hold and slide notes, so each pair has a unique group starting from
+    /// `1`. Other notes get group `0`.
+    ///
+    /// Pairs are validated first (See `long_note_pairs`), and nothing changes on error.
+    pub fn renumber_groups(&mut self, soundmap: &SoundMap) -> Result<(), SmapError> {
+        let pairs = self.long_note_pairs(soundmap)?;
+        if pairs.len() > u8::MAX as usize {
+            return Err(SmapError::InvalidChart(format!(
+                "Too many long notes: {}",
+                pairs.len()
+            )));
+        }
+
+        for note in &mut self.content {
+            note.group = 0;
+        }
+        for (group, (start, end)) in pairs.into_iter().enumerate() {
+            self.content[start].group = group as u8 + 1;
+            self.content[end].group = group as u8 + 1;
+        }
+        Ok(())
+    }
+
+    /// Fix hold and slide pairs whose end is before the start in time, by swapping sounds
+    /// (times) of the start and the end. It returns the count of fixed pairs.
+    ///
+    /// An inverted pair ends before it starts in time, so pairs are found in order of `content`.
+    /// (See `pair_long_notes`) If they can't be paired, nothing changes and it returns `0`.
+    pub fn fix_inverted_holds(&mut self, soundmap: &SoundMap) -> usize {
+        let Ok(pairs) = self.pair_long_notes(0..self.content.len()) else {
+            return 0;
+        };
+
+        let mut fixed = 0;
+        for (start, end) in pairs {
+            let start_time = self.content[start].sound.resolve_time(soundmap);
+            let end_time = self.content[end].sound.resolve_time(soundmap);
+            if let (Some(start_time), Some(end_time)) = (start_time, end_time)
+                && start_time > end_time
+            {
+                let end_sound = self.content[end].sound.clone();
+                self.content[end].sound =
+                    std::mem::replace(&mut self.content[start].sound, end_sound);
+                fixed += 1;
+            }
+        }
+        fixed
+    }
+
+    /// Get the smallest gap in ticks between consecutive judged notes on each lane. It is useful
+    /// for finding jacks which are too fast to hit.
+    ///
+    /// Lanes with less than 2 notes are omitted.
+    pub fn min_lane_gaps(&self, soundmap: &SoundMap) -> HashMap<u8, u32> {
+        let mut lanes: HashMap<u8, Vec<u32>> = HashMap::new();
+        for note in self.judged_notes() {
+            if let Some(time) = note.sound.resolve_time(soundmap) {
+                lanes.entry(note.lane).or_default().push(time);
+            }
+        }
+
+        lanes
+            .into_iter()
+            .filter_map(|(lane, mut times)| {
+                times.sort_unstable();
+                let gap = times.windows(2).map(|pair| pair[1] - pair[0]).min()?;
+                Some((lane, gap))
+            })
+            .collect()
+    }
+
+    /// Count notes in each `bucket_ms` from 0 to the end of the song, for density graphs. Fake
+    /// notes are counted only if `include_fakes` is set. It returns `(start seconds of the
+    /// bucket, note count)`.
+    ///
+    /// The song ends at the last note of the soundmap or the chart. Empty buckets are included,
+    /// so the series is continuous. Notes which can't be timed (ex. after a BPM of `0`) are
+    /// skipped. If `bucket_ms` is `0`, it returns an empty list.
+    pub fn density_series(
+        &self,
+        soundmap: &SoundMap,
+        bucket_ms: u32,
+        include_fakes: bool,
+    ) -> Vec<(f64, usize)> {
+        if bucket_ms == 0 {
+            return Vec::new();
+        }
+
+        let times_ms: Vec<f64> = self
+            .notes(include_fakes)
+            .filter_map(|n| n.sound.resolve_time(soundmap))
+            .map(|time| soundmap.tick_to_seconds(time) * 1000.0)
+            .filter(|ms| ms.is_finite())
+            .collect();
+        let soundmap_end = soundmap.notes.iter().map(|n| n.time).max().unwrap_or(0);
+        let soundmap_end_ms = soundmap.tick_to_seconds(soundmap_end) * 1000.0;
+        let end_ms = times_ms.iter().copied().fold(
+            if soundmap_end_ms.is_finite() {
+                soundmap_end_ms
+            } else {
+                0.0
+            },
+            f64::max,
+        );
+
+        let bucket_of = |ms: f64| (ms / f64::from(bucket_ms)) as usize;
+        let mut counts = vec![0; bucket_of(end_ms).saturating_add(1)];
+        for ms in times_ms {
+            counts[bucket_of(ms)] += 1;
+        }
+
+        counts
+            .into_iter()
+            .enumerate()
+            .map(|(index, count)| (index as f64 * f64::from(bucket_ms) / 1000.0, count))
+            .collect()
+    }
+
+    /// Get the fraction of judged notes on each lane. Fractions sum to `1.0`.
+    ///
+    /// Lanes without notes are omitted, and it returns an empty map if there are no notes.
+    pub fn lane_distribution(&self) -> HashMap<u8, f64> {
+        let mut counts: HashMap<u8, usize> = HashMap::new();
+        for note in self.judged_notes() {
+            *counts.entry(note.lane).or_insert(0) += 1;
+        }
+
+        let total: usize = counts.values().sum();
+        counts
+            .into_iter()
+            .map(|(lane, count)| (lane, count as f64 / total as f64))
+            .collect()
+    }
+
+    /// Get how evenly judged notes are spread across lanes, from `0.0` to `1.0`. (`1.0` is
+    /// perfectly even)
+    ///
+    /// It is the entropy of `lane_distribution`, normalized by the lane count. The lane count is
+    /// given by the chart type, or the count of used lanes if the type has no lane count. If
+    /// there are less than 2 lanes, it returns `0.0`.
+    pub fn lane_balance(&self) -> f64 {
+        let distribution = self.lane_distribution();
+        let lanes = find_chart_type(&self.chart_type)
+            .filter(|_| !self.custom_type)
+            .and_then(|info| info.lanes)
+            .map_or(0, usize::from)
+            .max(distribution.len());
+        if lanes < 2 {
+            return 0.0;
+        }
+
+        let entropy: f64 = distribution.values().map(|p| -p * p.ln()).sum();
+        entropy / (lanes as f64).ln()
+    }
+
+    /// Get a fingerprint of note timing, for finding same charts uploaded again.
+    ///
+    /// It hashes sorted `(time, lane)` of judged notes, and times are quantized to 10ms. So
+    /// charts which differ only a little in timing get same fingerprint. Metadata like name,
+    /// author or level are ignored, and sounds of notes too.
+    pub fn timing_fingerprint(&self, soundmap: &SoundMap) -> u64 {
+        const QUANTUM_MS: f64 = 10.0;
+
+        let mut notes: Vec<(u64, u8)> = self
+            .judged_notes()
+            .filter_map(|n| {
+                let time = n.sound.resolve_time(soundmap)?;
+                let ms = soundmap.tick_to_seconds(time) * 1000.0;
+                Some(((ms / QUANTUM_MS).round() as u64, n.lane))
+            })
+            .collect();
+        notes.sort_unstable();
+
+        let mut hasher = Fnv1a::default();
+        for (time, lane) in notes {
+            hasher.write_u64(time);
+            hasher.write_u8(lane);
+        }
+        hasher.finish()
+    }
+
+    /// Get notes on the lane.
+    pub fn notes_on_lane(&self, lane: u8) -> Vec<&PlayNote> {
+        self.content.iter().filter(|n| n.lane == lane).collect()
+    }
+
+    /// Move all notes on `from` lane to `to` lane. It returns the count of moved notes.
+    pub fn move_lane(&mut self, from: u8, to: u8) -> usize {
+        let mut moved = 0;
+        for note in self.content.iter_mut().filter(|n| n.lane == from) {
+            note.lane = to;
+            moved += 1;
+        }
+        moved
+    }
+
+    /// Same as `move_lane`, but it doesn't move anything if a note on `to` lane has same time
+    /// with a note on `from` lane. Times are resolved by `soundmap`, and notes which reference a
+    /// missing soundmap note don't collide.
+    pub fn move_lane_checked(
+        &mut self,
+        soundmap: &SoundMap,
+        from: u8,
+        to: u8,
+    ) -> Result<usize, SmapError> {
+        if from != to {
+            let targets: Vec<u32> = self
+                .notes_on_lane(to)
+                .into_iter()
+                .filter_map(|n| n.sound.resolve_time(soundmap))
+                .collect();
+            if let Some(time) = self
+                .notes_on_lane(from)
+                .into_iter()
+                .filter_map(|n| n.sound.resolve_time(soundmap))
+                .find(|time| targets.contains(time))
+            {
+                return Err(SmapError::LaneCollision { lane: to, time });
+            }
+        }
+
+        Ok(self.move_lane(from, to))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::soundmap::Bpm;
+
+    #[test]
+    fn canonical_type() {
+        let lower = Chart::new("Test", "Tester").with_chart_type("4k");
+        let upper = Chart::new("Test", "Tester").with_chart_type("4K");
+        let words = Chart::new("Test", "Tester").with_chart_type("Four Keys");
+        assert_eq!(lower.canonical_type(), "4K");
+        assert_eq!(lower.canonical_type(), upper.canonical_type());
+        assert_eq!(words.canonical_type(), "4K");
+        assert_eq!(Chart::default().canonical_type(), "Plain");
+
+        let unknown = Chart::new("Test", "Tester").with_chart_type("Drum");
+        assert_eq!(unknown.canonical_type(), "Drum");
+        assert!(unknown.has_unknown_type());
+
+        let custom = Chart::new("Test", "Tester").with_custom_type("4k");
+        assert_eq!(custom.canonical_type(), "4k");
+        assert!(!custom.has_unknown_type());
+    }
+
+    #[test]
+    fn judgment_windows() {
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(0, 192, 0);
+
+        let mut chart = Chart::new("Test", "Tester");
+        chart.insert_note(0, 0);
+        chart.insert_silent_note(1, 384);
+        chart.insert_note(2, 5);
+        chart
+            .content
+            .push(PlayNote::new().with_lane(3).with_time(192).fake());
+
+        let windows = chart.judgment_windows(&soundmap, &[50.0, 100.0]);
+        assert_eq!(windows.len(), 4);
+
+        // A beat in 120 BPM is 500ms
+        let (center, bounds) = windows[0].as_ref().unwrap();
+        assert_eq!(*center, 500.0);
+        assert_eq!(*bounds, vec![450.0, 550.0, 400.0, 600.0]);
+        assert_eq!(windows[1].as_ref().unwrap().0, 1000.0);
+
+        // A missing soundmap note and a fake note
+        assert_eq!(windows[2], None);
+        assert_eq!(windows[3], None);
+    }
+
+    #[test]
+    fn fake_notes() {
+        let mut chart = Chart::new("Test", "Tester");
+        chart.insert_note(0, 0);
+        chart
+            .content
+            .push(PlayNote::new().with_lane(1).with_time(192).fake());
+        chart.insert_note(2, 1);
+
+        assert_eq!(chart.judged_notes().count(), 2);
+        assert_eq!(chart.note_count(false), 2);
+        assert_eq!(chart.note_count(true), 3);
+
+        let json = serde_json::to_string(&chart).unwrap();
+        let loaded: Chart = serde_json::from_str(&json).unwrap();
+        assert!(loaded.content[1].fake);
+        assert!(!loaded.content[0].fake);
+
+        // Notes without the flag
+        let note: PlayNote = serde_json::from_str(
+            r#"{"sound":{"smapNoteId":null,"time":0},"noteType":0,"group":0,"lane":0}"#,
+        )
+        .unwrap();
+        assert!(!note.fake);
+    }
+
+    #[test]
+    fn resolve_base() {
+        let mut hard = Chart::new("Hard", "Tester");
+        hard.insert_note(0, 0);
+        hard.insert_note(1, 1);
+        let mut hard_plus = Chart::new("Hard+", "Tester").with_base("Hard");
+        hard_plus.insert_note(2, 2);
+        hard_plus.insert_note(3, 3);
+        let charts = vec![hard.clone(), hard_plus.clone()];
+
+        let resolved = hard_plus.resolve_base(&charts).unwrap();
+        assert_eq!(resolved.name, "Hard+");
+        assert_eq!(resolved.base, None);
+        assert_eq!(resolved.content.len(), 4);
+        assert_eq!(resolved.content[0].lane, 0);
+        assert_eq!(resolved.content[3].lane, 3);
+
+        // Without base
+        assert_eq!(hard.resolve_base(&charts).unwrap().content.len(), 2);
+
+        let json = serde_json::to_string(&hard_plus).unwrap();
+        let loaded: Chart = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.base.as_deref(), Some("Hard"));
+    }
+
+    #[test]
+    fn resolve_base_error() {
+        let first = Chart::new("First", "Tester").with_base("Second");
+        let second = Chart::new("Second", "Tester").with_base("First");
+        let charts = vec![first.clone(), second];
+        assert!(matches!(
+            first.resolve_base(&charts),
+            Err(SmapError::ChartCycle(_))
+        ));
+
+        let orphan = Chart::new("Orphan", "Tester").with_base("Missing");
+        assert!(matches!(
+            orphan.resolve_base(&charts),
+            Err(SmapError::ChartNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn densest_measure() {
+        let mut soundmap = SoundMap::new();
+        let mut chart = Chart::new("Test", "Tester");
+        assert_eq!(chart.densest_measure(&soundmap, false), None);
+
+        // 1 note in bar 0 and bar 1, 4 notes in bar 2
+        for time in [0, 768, 1536, 1728, 1920, 2112] {
+            soundmap.insert_note(0, time, 0);
+        }
+        for id in 0..6 {
+            chart.insert_note(0, id);
+        }
+        assert_eq!(chart.densest_measure(&soundmap, false), Some((2, 4)));
+
+        // Fake notes are counted only if included
+        chart.content[1].fake = true;
+        assert_eq!(chart.densest_measure(&soundmap, false), Some((2, 4)));
+        for index in 2..5 {
+            chart.content[index].fake = true;
+        }
+        assert_eq!(chart.densest_measure(&soundmap, false), Some((0, 1)));
+        assert_eq!(chart.densest_measure(&soundmap, true), Some((2, 4)));
+
+        // Ties return the earliest
+        chart.content.truncate(2);
+        assert_eq!(chart.densest_measure(&soundmap, true), Some((0, 1)));
+    }
+
+    #[test]
+    fn plausibility_check() {
+        let mut soundmap = SoundMap::new();
+        let mut chart = Chart::new("Test", "Tester").with_level(1);
+
+        // 32 notes in about 2 seconds (120 BPM, 768 ticks)
+        for i in 0..32 {
+            soundmap.insert_note(0, i * 24, 0);
+            chart.insert_note(0, i as u16);
+        }
+        let warnings = chart.plausibility_check(&soundmap);
+        assert_eq!(warnings.len(), 1);
+        assert!(matches!(
+            warnings[0],
+            PlausibilityWarning::TooDense { level: 1, .. }
+        ));
+
+        // Fine for a high level
+        chart.difficulty_level = 12;
+        assert!(chart.plausibility_check(&soundmap).is_empty());
+
+        // Custom table
+        let ranges = [DensityRange {
+            levels: 12..=12,
+            notes_per_second: 50.0..=100.0,
+        }];
+        assert!(matches!(
+            chart.plausibility_check_with(&soundmap, &ranges)[0],
+            PlausibilityWarning::TooSparse { level: 12, .. }
+        ));
+    }
+
+    #[test]
+    fn renumber_groups() {
+        let note = |lane, note_type| PlayNote::new().with_lane(lane).with_type(note_type);
+        let mut chart = Chart::new("Test", "Tester");
+        chart.content = vec![
+            note(0, NOTE_HOLD_START),
+            note(1, NOTE_HOLD_START),
+            note(2, NOTE_NORMAL).with_group(5),
+            note(0, NOTE_HOLD_END),
+            note(1, NOTE_HOLD_END_FLICK),
+        ];
+
+        let soundmap = SoundMap::new();
+        chart.renumber_groups(&soundmap).unwrap();
+        let groups: Vec<u8> = chart.content.iter().map(|n| n.group).collect();
+        assert_eq!(groups, vec![1, 2, 0, 1, 2]);
+
+        // Unpaired
+        chart.content.pop();
+        assert!(chart.renumber_groups(&soundmap).is_err());
+        assert_eq!(chart.content[1].group, 2);
+    }
+
+    #[test]
+    fn renumber_groups_in_time_order() {
+        let note = |note_type, time| {
+            PlayNote::new()
+                .with_lane(0)
+                .with_type(note_type)
+                .with_time(time)
+        };
+        let mut chart = Chart::new("Test", "Tester");
+        chart.content = vec![
+            note(NOTE_HOLD_START, 0),
+            note(NOTE_HOLD_START, 384),
+            note(NOTE_HOLD_END, 192),
+            note(NOTE_HOLD_END, 576),
+        ];
+
+        // In order of content, the second hold would start before the first one ends
+        chart.renumber_groups(&SoundMap::new()).unwrap();
+        let groups: Vec<u8> = chart.content.iter().map(|n| n.group).collect();
+        assert_eq!(groups, vec![1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn chart_preview() {
+        let chart = Chart::new("Hard", "Tester").with_preview_start(30000);
+        let json = serde_json::to_string(&chart).unwrap();
+        let loaded: Chart = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.preview_start_ms, Some(30000));
+
+        let mut manifest = Manifest::new("Test", "Tester");
+        manifest.preview_start_ms = Some(15000);
+        assert_eq!(loaded.effective_preview(&manifest), Some(30000));
+    }
+
+    #[test]
+    fn chart_preview_fallback() {
+        let mut manifest = Manifest::new("Test", "Tester");
+        let chart = Chart::new("Normal", "Tester");
+        assert_eq!(chart.effective_preview(&manifest), None);
+
+        manifest.preview_start_ms = Some(15000);
+        assert_eq!(chart.effective_preview(&manifest), Some(15000));
+    }
+
+    #[test]
+    fn scroll_velocities() {
+        let mut chart = Chart::new("Test", "Tester");
+        chart.add_sv(768, 0.5);
+        chart.add_sv(0, 2.0);
+
+        let json = serde_json::to_string(&chart).unwrap();
+        let loaded: Chart = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            loaded.scroll_velocities,
+            vec![ScrollVelocity::new(0, 2.0), ScrollVelocity::new(768, 0.5)]
+        );
+
+        // Old charts without SV
+        let json = serde_json::to_string(&Chart::new("Old", "Tester")).unwrap();
+        assert!(!json.contains("scrollVelocities"));
+        let loaded: Chart = serde_json::from_str(&json).unwrap();
+        assert!(loaded.scroll_velocities.is_empty());
+    }
+
+    #[test]
+    fn sound_override() {
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(3, 0, 0);
+
+        let mut chart = Chart::new("Test", "Tester");
+        chart.insert_note(0, 0);
+        chart
+            .content
+            .push(PlayNote::new().with_sound(0).with_sound_override(5));
+        chart
+            .content
+            .push(PlayNote::new().with_sound_override(6).with_time(96));
+
+        let json = serde_json::to_string(&chart).unwrap();
+        let loaded: Chart = serde_json::from_str(&json).unwrap();
+        let sounds: Vec<Option<u16>> = loaded
+            .content
+            .iter()
+            .map(|n| n.sound.resolve_sound(&soundmap))
+            .collect();
+        assert_eq!(sounds, vec![Some(3), Some(5), Some(6)]);
+
+        // Old notes without override
+        let loaded: NoteSound = serde_json::from_str(r#"{"smapNoteId":null,"time":96}"#).unwrap();
+        assert_eq!(loaded.sound_override, None);
+        assert_eq!(loaded.resolve_sound(&soundmap), None);
+        assert!(
+            !serde_json::to_string(&loaded)
+                .unwrap()
+                .contains("soundOverride")
+        );
+    }
+
+    #[test]
+    fn materialize_times() {
+        let soundmap = SoundMap::from_notes([(0, 192, 0), (0, 384, 0)]);
+        let mut chart = Chart::new("Test", "Tester");
+        chart.insert_note(0, 1);
+        chart.insert_silent_note(1, 96);
+        chart.insert_note(2, 0);
+
+        chart.materialize_times(&soundmap).unwrap();
+        assert!(chart.content.iter().all(|n| n.sound.smap_note_id.is_none()));
+        let times: Vec<u32> = chart.content.iter().map(|n| n.sound.time).collect();
+        assert_eq!(times, vec![384, 96, 192]);
+
+        // Dangling
+        let mut chart = Chart::new("Test", "Tester");
+        chart.insert_note(0, 0);
+        chart.insert_note(0, 5);
+        assert!(chart.materialize_times(&soundmap).is_err());
+        assert_eq!(chart.content[0].sound.smap_note_id, Some(0));
+    }
+
+    #[test]
+    fn fix_inverted_holds() {
+        let soundmap = SoundMap::from_notes([(0, 0, 0), (0, 192, 0), (0, 384, 0), (0, 576, 0)]);
+        let note = |lane, note_type, id| {
+            PlayNote::new()
+                .with_lane(lane)
+                .with_type(note_type)
+                .with_sound(id)
+        };
+        let mut chart = Chart::new("Test", "Tester");
+        chart.content = vec![
+            note(0, NOTE_HOLD_START, 0),
+            note(0, NOTE_HOLD_END, 1),
+            // Inverted
+            note(1, NOTE_HOLD_START, 3),
+            note(1, NOTE_HOLD_END, 2),
+        ];
+        // Can't be paired in time order before fixing
+        assert!(chart.long_note_pairs(&soundmap).is_err());
+
+        assert_eq!(chart.fix_inverted_holds(&soundmap), 1);
+        let ids: Vec<Option<u16>> = chart.content.iter().map(|n| n.sound.smap_note_id).collect();
+        assert_eq!(ids, vec![Some(0), Some(1), Some(2), Some(3)]);
+        assert_eq!(chart.content[2].note_type, NOTE_HOLD_START);
+        assert_eq!(
+            chart.long_note_pairs(&soundmap).unwrap(),
+            vec![(0, 1), (2, 3)]
+        );
+
+        assert_eq!(chart.fix_inverted_holds(&soundmap), 0);
+    }
+
+    /// A chart which has one dangling note (id 5) between valid notes.
+    fn dangling_chart() -> (SoundMap, Chart) {
+        let soundmap = SoundMap::from_notes([(0, 0, 0), (0, 192, 0), (0, 384, 0), (0, 576, 0)]);
+        let mut chart = Chart::new("Test", "Tester");
+        chart.insert_note(0, 0);
+        chart.insert_note(1, 5);
+        chart.insert_note(2, 2);
+        (soundmap, chart)
+    }
+
+    #[test]
+    fn validate_against_soundmap() {
+        let (soundmap, mut chart) = dangling_chart();
+        assert_eq!(chart.validate_against(&soundmap), Err(vec![5]));
+
+        chart.insert_note(3, 5);
+        chart.insert_silent_note(0, 768);
+        assert_eq!(chart.validate_against(&soundmap), Err(vec![5]));
+
+        chart.repair_dangling(&soundmap, DanglingStrategy::DropNote);
+        assert!(chart.validate_against(&soundmap).is_ok());
+    }
+
+    #[test]
+    fn repair_dangling_drop() {
+        let (soundmap, mut chart) = dangling_chart();
+        assert_eq!(
+            chart.repair_dangling(&soundmap, DanglingStrategy::DropNote),
+            1
+        );
+        assert_eq!(chart.content.len(), 2);
+        assert_eq!(chart.content[1].lane, 2);
+    }
+
+    #[test]
+    fn repair_dangling_explicit_time() {
+        let (soundmap, mut chart) = dangling_chart();
+        let strategy = DanglingStrategy::ConvertToExplicitTime(96);
+        assert_eq!(chart.repair_dangling(&soundmap, strategy), 1);
+        assert_eq!(
+            chart.content[1].sound,
+            NoteSound {
+                smap_note_id: None,
+                time: 96,
+                sound_override: None,
+            }
+        );
+        assert_eq!(chart.repair_dangling(&soundmap, strategy), 0);
+    }
+
+    #[test]
+    fn repair_dangling_nearest() {
+        let (soundmap, mut chart) = dangling_chart();
+        assert_eq!(
+            chart.repair_dangling(&soundmap, DanglingStrategy::NearestNote),
+            1
+        );
+        assert_eq!(chart.content[1].sound.smap_note_id, Some(3));
+        assert_eq!(chart.content[0].sound.smap_note_id, Some(0));
+    }
+
+    #[test]
+    fn min_lane_gaps() {
+        let soundmap = SoundMap::from_notes([(0, 0, 0), (0, 192, 0), (0, 200, 0), (0, 384, 0)]);
+        let mut chart = Chart::new("Test", "Tester");
+        chart.insert_note(0, 0);
+        chart.insert_note(0, 3);
+        // Double-tap on lane 1
+        chart.insert_note(1, 2);
+        chart.insert_note(1, 1);
+        chart.insert_note(2, 0);
+
+        let gaps = chart.min_lane_gaps(&soundmap);
+        assert_eq!(gaps.len(), 2);
+        assert_eq!(gaps[&0], 384);
+        assert_eq!(gaps[&1], 8);
+    }
+
+    #[test]
+    fn lane_balance() {
+        let mut one_lane = Chart::new("Test", "Tester").with_chart_type("4K");
+        for id in 0..8 {
+            one_lane.insert_note(0, id);
+        }
+        assert_eq!(one_lane.lane_distribution()[&0], 1.0);
+        assert!(one_lane.lane_balance() < 0.01);
+
+        let mut even = Chart::new("Test", "Tester").with_chart_type("4K");
+        for id in 0..8 {
+            even.insert_note((id % 4) as u8, id);
+        }
+        assert!((even.lane_balance() - 1.0).abs() < 1e-9);
+        assert_eq!(even.lane_distribution()[&3], 0.25);
+
+        // Two of the 4 lanes are unused
+        let mut half = Chart::new("Test", "Tester").with_chart_type("4K");
+        for id in 0..8 {
+            half.insert_note((id % 2) as u8, id);
+        }
+        assert!((half.lane_balance() - 0.5).abs() < 1e-9);
+
+        assert_eq!(Chart::new("Empty", "Tester").lane_balance(), 0.0);
+        assert!(Chart::new("Empty", "Tester").lane_distribution().is_empty());
+    }
+
+    #[test]
+    fn density_series() {
+        // 0ms, 100ms, 250ms and 2000ms in 120 BPM
+        let soundmap = SoundMap::from_notes([(0, 0, 0), (0, 38, 0), (0, 96, 0), (0, 768, 0)]);
+        let mut chart = Chart::new("Test", "Tester");
+        for id in 0..3 {
+            chart.insert_note(0, id);
+        }
+
+        let series = chart.density_series(&soundmap, 500, false);
+        // Until the last note of the soundmap
+        assert_eq!(series.len(), 5);
+        assert_eq!(series[0], (0.0, 3));
+        assert_eq!(series[1], (0.5, 0));
+        assert_eq!(series[4].1, 0);
+        assert!(chart.density_series(&soundmap, 0, false).is_empty());
+
+        // Fake notes are counted only if included
+        chart.content[0].fake = true;
+        assert_eq!(chart.density_series(&soundmap, 500, false)[0], (0.0, 2));
+        assert_eq!(chart.density_series(&soundmap, 500, true)[0], (0.0, 3));
+
+        // Notes after a BPM of 0 are skipped
+        let mut stopped = soundmap.clone();
+        stopped.bpm = vec![Bpm::new(120.0, 0), Bpm::new(0.0, 90)];
+        let series = chart.density_series(&stopped, 500, true);
+        assert_eq!(series, vec![(0.0, 2)]);
+    }
+
+    #[test]
+    fn timing_fingerprint() {
+        let soundmap = SoundMap::from_notes([(0, 0, 0), (1, 96, 0), (0, 192, 0)]);
+        let mut chart = Chart::new("Normal", "Tester").with_level(3);
+        chart.insert_note(0, 0);
+        chart.insert_note(1, 1);
+        chart.insert_silent_note(2, 192);
+
+        // Same notes in other order, with other metadata
+        let mut copied = Chart::new("Uploaded again", "Someone").with_level(9);
+        copied.insert_silent_note(2, 192);
+        copied.insert_note(1, 1);
+        copied.insert_note(0, 0);
+        assert_eq!(
+            chart.timing_fingerprint(&soundmap),
+            copied.timing_fingerprint(&soundmap)
+        );
+
+        copied.move_lane(2, 3);
+        assert_ne!(
+            chart.timing_fingerprint(&soundmap),
+            copied.timing_fingerprint(&soundmap)
+        );
+    }
+
+    #[test]
+    fn estimate_difficulty() {
+        // 1 note per second on 4 lanes
+        let sparse_map = SoundMap::from_notes((0..16).map(|i| (0, i * 384, 0)));
+        let mut sparse = Chart::new("Easy", "Tester");
+        for id in 0..16 {
+            sparse.insert_note((id % 4) as u8, id);
+        }
+
+        // 16 notes per second on 7 lanes, with some jacks
+        let dense_map = SoundMap::from_notes((0..64).map(|i| (0, i * 24, 0)));
+        let mut dense = Chart::new("Hard", "Tester");
+        for id in 0..64 {
+            dense.insert_note((id % 7) as u8, id);
+            if id % 8 == 0 {
+                dense.insert_note(6, id);
+            }
+        }
+
+        let easy = sparse.estimate_difficulty(&sparse_map);
+        let hard = dense.estimate_difficulty(&dense_map);
+        assert!((1..=20).contains(&easy));
+        assert!((1..=20).contains(&hard));
+        assert!(hard > easy);
+        assert_eq!(
+            Chart::new("Empty", "Tester").estimate_difficulty(&sparse_map),
+            1
+        );
+    }
+
+    #[test]
+    fn from_soundmap_by_track() {
+        let soundmap = SoundMap::from_notes([(0, 0, 5), (1, 96, 2), (2, 192, 9), (0, 384, 5)]);
+
+        let chart = Chart::from_soundmap_by_track(&soundmap);
+        let notes: Vec<(u8, Option<u16>)> = chart
+            .content
+            .iter()
+            .map(|n| (n.lane, n.sound.smap_note_id))
+            .collect();
+        assert_eq!(
+            notes,
+            vec![(1, Some(0)), (0, Some(1)), (2, Some(2)), (1, Some(3))]
+        );
+        assert_eq!(chart.chart_type, "Plain");
+
+        let wrapped = Chart::from_soundmap_by_track_with(&soundmap, 2, true).unwrap();
+        assert_eq!(wrapped.content[2].lane, 0);
+        assert!(Chart::from_soundmap_by_track_with(&soundmap, 2, false).is_err());
+    }
+
+    #[test]
+    fn notes_on_lane() {
+        let mut chart = Chart::new("Test", "Tester");
+        chart.insert_note(0, 0);
+        chart.insert_note(1, 1);
+        chart.insert_silent_note(0, 192);
+
+        let notes = chart.notes_on_lane(0);
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].sound.smap_note_id, Some(0));
+        assert_eq!(notes[1].sound.time, 192);
+        assert!(chart.notes_on_lane(2).is_empty());
+    }
+
+    #[test]
+    fn move_lane() {
+        let mut chart = Chart::new("Test", "Tester");
+        chart.insert_note(0, 0);
+        chart.insert_note(0, 1);
+        chart.insert_note(1, 2);
+
+        assert_eq!(chart.move_lane(0, 3), 2);
+        assert!(chart.notes_on_lane(0).is_empty());
+        assert_eq!(chart.notes_on_lane(3).len(), 2);
+        assert_eq!(chart.notes_on_lane(1).len(), 1);
+    }
+
+    #[test]
+    fn move_lane_collision() {
+        let mut chart = Chart::new("Test", "Tester");
+        chart.insert_silent_note(0, 192);
+        chart.insert_silent_note(1, 192);
+        chart.insert_silent_note(2, 384);
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(0, 384, 0);
+
+        assert!(matches!(
+            chart.move_lane_checked(&soundmap, 0, 1),
+            Err(SmapError::LaneCollision { lane: 1, .. })
+        ));
+        assert_eq!(chart.notes_on_lane(0).len(), 1);
+
+        assert_eq!(chart.move_lane_checked(&soundmap, 0, 2).unwrap(), 1);
+        assert_eq!(chart.notes_on_lane(2).len(), 2);
+
+        // A soundmap note on same time collides with a silent note
+        chart.insert_note(3, 0);
+        assert!(chart.move_lane_checked(&soundmap, 3, 2).is_err());
+        assert_eq!(chart.move_lane_checked(&soundmap, 3, 4).unwrap(), 1);
+    }
 }
@@ -3,6 +3,18 @@
 //! It contains JSON data
 
 use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use crate::error::SmapError;
+use crate::types::next_free_id;
+use crate::types::soundmap::{Instrument, SoundMap};
+
+/// A generator stamp of this library. (See `Manifest.generator`)
+pub const GENERATOR: &str = concat!("rg_soundmap ", env!("CARGO_PKG_VERSION"));
+
+/// A range of pitches in MIDI GM Drummap. (35: Acoustic Bass Drum ~ 81: Open Triangle)
+pub const GM_DRUM_PITCHES: RangeInclusive<u8> = 35..=81;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Sound {
@@ -13,6 +25,114 @@ pub struct Sound {
     /// for example, C4(= Middle C) note goes 60 in decimal. It same as MIDI standard.
     /// If it is drum sound, it follows MIDI GM Drummap.
     pub pitch: u8,
+
+    /// Microtonal tuning in cents relative to `pitch`. (-100 ~ 100)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cents: Option<i16>,
+
+    /// A start of the loop in sample frames, for sustained samples.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loop_start: Option<u32>,
+
+    /// An end of the loop in sample frames. It must be after `loop_start`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loop_end: Option<u32>,
+}
+
+impl Sound {
+    pub fn new(id: u16, path: &str, pitch: u8) -> Self {
+        Self {
+            id,
+            path: path.to_string(),
+            pitch,
+            cents: None,
+            loop_start: None,
+            loop_end: None,
+        }
+    }
+
+    /// Set loop points in sample frames. It fails if `start` is not before `end`.
+    pub fn with_loop_points(mut self, start: u32, end: u32) -> Result<Self, SmapError> {
+        if start >= end {
+            return Err(SmapError::InvalidLoopPoints { start, end });
+        }
+        self.loop_start = Some(start);
+        self.loop_end = Some(end);
+        Ok(self)
+    }
+
+    /// Whether loop points are both unset, or both set with start before end.
+    pub fn has_valid_loop_points(&self) -> bool {
+        match (self.loop_start, self.loop_end) {
+            (None, None) => true,
+            (Some(start), Some(end)) => start < end,
+            _ => false,
+        }
+    }
+
+    /// Whether the sound is percussion, not pitched.
+    ///
+    /// It checks instruments of tracks which have notes of this sound. If there are no tagged
+    /// instruments (or only `SomeElse`), it checks whether `pitch` is in the GM Drummap range.
+    pub fn is_percussion(&self, soundmap: &SoundMap) -> bool {
+        let instruments: Vec<&Instrument> = soundmap
+            .notes
+            .iter()
+            .filter(|n| n.sound_id == self.id)
+            .filter_map(|n| soundmap.track_tags.iter().find(|t| t.id == n.track))
+            .map(|t| &t.instrument)
+            .filter(|i| !matches!(i, Instrument::SomeElse))
+            .collect();
+
+        if instruments.is_empty() {
+            GM_DRUM_PITCHES.contains(&self.pitch)
+        } else {
+            instruments.iter().any(|i| i.is_percussion())
+        }
+    }
+
+    /// Whether `cents` is in the range of -100 ~ 100.
+    pub fn has_valid_cents(&self) -> bool {
+        self.cents.is_none_or(|cents| (-100..=100).contains(&cents))
+    }
+}
+
+/// A sound whose file extension doesn't match `SoundMap.audio_format`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtMismatch {
+    pub sound_id: u16,
+    pub path: String,
+
+    /// The declared audio format
+    pub audio_format: String,
+
+    /// Extensions which are expected for the format
+    pub expected: &'static [&'static str],
+}
+
+/// Get file extensions of the audio format. (See `SoundMap.audio_format`)
+///
+/// It returns `None` if the format is unknown.
+pub fn audio_format_extensions(audio_format: &str) -> Option<&'static [&'static str]> {
+    match audio_format.to_lowercase().as_str() {
+        "wav" => Some(&["wav"]),
+        "wav-bwf" => Some(&["wav", "bwf"]),
+        "mp3" => Some(&["mp3"]),
+        "ogg" => Some(&["ogg", "oga"]),
+        "ogg-flac" => Some(&["ogg", "oga", "flac"]),
+        "opus" => Some(&["opus", "ogg"]),
+        "aac" => Some(&["aac", "m4a", "mp4"]),
+        "aiff" => Some(&["aiff", "aif", "aifc"]),
+        _ => None,
+    }
+}
+
+/// A pitch of a sound which is changed by `Manifest::apply_gm_drummap`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PitchRemap {
+    pub sound_id: u16,
+    pub from: u8,
+    pub to: u8,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -30,6 +150,33 @@ pub struct Manifest {
     pub sounds: Vec<Sound>,
 
     pub genre: String,
+
+    /// A start of the song preview in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview_start_ms: Option<u32>,
+
+    /// A tool which made the file (ex. `rg_soundmap 0.7.0`)
+    ///
+    /// If it isn't set, `GENERATOR` is set on save.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generator: Option<String>,
+
+    /// A license of the package (ex. `CC-BY-4.0`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+
+    /// A URL where the package or its sounds are from, for attribution.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
+}
+
+fn push_unique(names: &mut Vec<String>, name: &str) -> bool {
+    let lower = name.to_lowercase();
+    if names.iter().any(|n| n.to_lowercase() == lower) {
+        return false;
+    }
+    names.push(name.to_string());
+    true
 }
 
 impl Default for Manifest {
@@ -40,16 +187,21 @@ impl Default for Manifest {
             writers: Vec::new(),
             sounds: Vec::new(),
             genre: String::new(),
+            preview_start_ms: None,
+            generator: None,
+            license: None,
+            source_url: None,
         }
     }
 }
 
 impl Manifest {
     pub fn new(title: &str, artist: &str) -> Self {
-        let mut manifest = Self::default();
-        manifest.title = title.to_string();
-        manifest.artists = vec![artist.to_string()];
-        manifest
+        Self {
+            title: title.to_string(),
+            artists: vec![artist.to_string()],
+            ..Self::default()
+        }
     }
 
     pub fn with_artists(mut self, artists: Vec<String>) -> Self {
@@ -62,60 +214,154 @@ impl Manifest {
         self
     }
 
-    pub fn add_artist(&mut self, artist: &str) {
-        self.artists.push(artist.to_string());
+    pub fn with_generator(mut self, generator: &str) -> Self {
+        self.generator = Some(generator.to_string());
+        self
     }
 
-    pub fn add_writer(&mut self, writer: &str) {
-        self.writers.push(writer.to_string());
+    pub fn with_license(mut self, license: &str) -> Self {
+        self.license = Some(license.to_string());
+        self
     }
 
-    pub fn insert_sound(&mut self, id: u16, path: &str, pitch: u8) {
-        let target_pos = self.sounds.iter().position(|x| x.id == id).unwrap();
-        self.sounds.insert(
-            target_pos,
-            Sound {
-                id,
-                path: path.to_string(),
-                pitch,
-            },
-        );
+    pub fn with_source_url(mut self, source_url: &str) -> Self {
+        self.source_url = Some(source_url.to_string());
+        self
+    }
+
+    /// Add an artist if the name isn't in the list. (Case-insensitive)
+    ///
+    /// It returns whether the artist is added.
+    pub fn add_artist(&mut self, artist: &str) -> bool {
+        push_unique(&mut self.artists, artist)
     }
 
-    pub fn push_sound(&mut self, path: &str, pitch: u8) {
-        let mut ids: Vec<u16> = Vec::new();
+    /// Add a writer if the name isn't in the list. (Case-insensitive)
+    ///
+    /// It returns whether the writer is added.
+    pub fn add_writer(&mut self, writer: &str) -> bool {
+        push_unique(&mut self.writers, writer)
+    }
 
-        for sound in &self.sounds {
-            ids.push(sound.id);
+    /// Insert a sound with `id`, keeping sounds sorted by id.
+    ///
+    /// If a sound with `id` already exists, it is replaced in place, and the old one is returned.
+    /// If not, the sound is inserted before the first sound which has a larger id, or pushed to
+    /// the end.
+    pub fn insert_sound(&mut self, id: u16, path: &str, pitch: u8) -> Option<Sound> {
+        let sound = Sound::new(id, path, pitch);
+        if let Some(existing) = self.sounds.iter_mut().find(|s| s.id == id) {
+            return Some(std::mem::replace(existing, sound));
         }
 
-        if self.sounds.len() == 0 {
-            self.sounds.push(Sound {
-                id: 0,
-                path: path.to_string(),
-                pitch,
-            });
-        } else {
-            for (index, sound_id) in ids.iter().enumerate() {
-                // If missing number (0:0, 1:1, '2:3', 3:4 ...)
-                if index != *sound_id as usize {
-                    self.sounds.push(Sound {
-                        id: index as u16,
-                        path: path.to_string(),
-                        pitch,
-                    });
-                    break;
-                }
-                // If last index
-                else if index == (ids.len() - 1) {
-                    self.sounds.push(Sound {
-                        id: (index as u16) + 1,
-                        path: path.to_string(),
-                        pitch,
-                    });
-                }
+        match self.sounds.iter().position(|s| s.id > id) {
+            Some(index) => self.sounds.insert(index, sound),
+            None => self.sounds.push(sound),
+        }
+        None
+    }
+
+    /// Remove the sound with `id`, and return it.
+    ///
+    /// Notes of the soundmap which use the sound are not changed, so they reference a missing
+    /// sound after removing. Check `is_sound_referenced` first.
+    pub fn remove_sound(&mut self, id: u16) -> Option<Sound> {
+        let index = self.sounds.iter().position(|s| s.id == id)?;
+        Some(self.sounds.remove(index))
+    }
+
+    /// Whether a note of the soundmap uses the sound with `id`.
+    ///
+    /// `NoteSound.sound_override` of charts is not checked.
+    pub fn is_sound_referenced(&self, id: u16, soundmap: &SoundMap) -> bool {
+        soundmap.notes.iter().any(|n| n.sound_id == id)
+    }
+
+    /// Add a sound with the smallest unused id, and return the id.
+    ///
+    /// If every id is used, nothing is added and it returns `None`.
+    pub fn push_sound(&mut self, path: &str, pitch: u8) -> Option<u16> {
+        let ids: Vec<u16> = self.sounds.iter().map(|s| s.id).collect();
+        let id = next_free_id(&ids)?;
+        self.sounds.push(Sound::new(id, path, pitch));
+        Some(id)
+    }
+
+    /// Sort sounds by id, for a clean save.
+    pub fn normalize(&mut self) {
+        self.sounds.sort_by_key(|s| s.id);
+    }
+
+    /// Find sounds whose file extension doesn't match `audio_format` of the soundmap.
+    /// (Case-insensitive)
+    ///
+    /// Nothing is checked if the format is unknown.
+    pub fn validate_extensions(&self, soundmap: &SoundMap) -> Vec<ExtMismatch> {
+        let Some(expected) = audio_format_extensions(&soundmap.audio_format) else {
+            return Vec::new();
+        };
+
+        self.sounds
+            .iter()
+            .filter(|sound| {
+                let extension = Path::new(&sound.path)
+                    .extension()
+                    .map(|e| e.to_string_lossy().to_lowercase());
+                !extension.is_some_and(|e| expected.contains(&e.as_str()))
+            })
+            .map(|sound| ExtMismatch {
+                sound_id: sound.id,
+                path: sound.path.clone(),
+                audio_format: soundmap.audio_format.clone(),
+                expected,
+            })
+            .collect()
+    }
+
+    /// Snap pitches of drum sounds to MIDI GM Drummap, for sounds from imports which use other
+    /// pitches. (See `Instrument::gm_drum_pitches`)
+    ///
+    /// The instrument of a sound is given by the track tag of the first note which uses the
+    /// sound. Each pitch is snapped to the nearest pitch of the instrument, or the lower one if
+    /// two are same distance. Sounds on other tracks are not changed. It returns changed pitches.
+    pub fn apply_gm_drummap(&mut self, soundmap: &SoundMap) -> Vec<PitchRemap> {
+        let mut remaps = Vec::new();
+        for sound in &mut self.sounds {
+            let Some(instrument) = soundmap
+                .notes
+                .iter()
+                .filter(|n| n.sound_id == sound.id)
+                .find_map(|n| soundmap.track_tags.iter().find(|t| t.id == n.track))
+                .map(|t| &t.instrument)
+            else {
+                continue;
+            };
+            let Some(&pitch) = instrument
+                .gm_drum_pitches()
+                .iter()
+                .min_by_key(|p| p.abs_diff(sound.pitch))
+            else {
+                continue;
+            };
+
+            if pitch != sound.pitch {
+                remaps.push(PitchRemap {
+                    sound_id: sound.id,
+                    from: sound.pitch,
+                    to: pitch,
+                });
+                sound.pitch = pitch;
             }
         }
+        remaps
+    }
+
+    /// Get percussion sounds. (See `Sound::is_percussion`)
+    pub fn percussion_sounds(&self, soundmap: &SoundMap) -> Vec<&Sound> {
+        self.sounds
+            .iter()
+            .filter(|s| s.is_percussion(soundmap))
+            .collect()
     }
 
     pub fn get_sound_path(&self, id: u16) -> Option<&str> {
@@ -127,3 +373,205 @@ impl Manifest {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_unique_names() {
+        let mut manifest = Manifest::new("Test", "A");
+        assert!(!manifest.add_artist("A"));
+        assert!(!manifest.add_artist("a"));
+        assert_eq!(manifest.artists.len(), 1);
+        assert!(manifest.add_artist("B"));
+        assert_eq!(manifest.artists, vec!["A", "B"]);
+
+        assert!(manifest.add_writer("A"));
+        assert!(!manifest.add_writer("A"));
+        assert_eq!(manifest.writers.len(), 1);
+    }
+
+    #[test]
+    fn license_round_trip() {
+        let manifest = Manifest::new("Test", "Tester")
+            .with_license("CC-BY-4.0")
+            .with_source_url("https://example.com/test");
+        let json = serde_json::to_string(&manifest).unwrap();
+        let loaded: Manifest = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.license.as_deref(), Some("CC-BY-4.0"));
+        assert_eq!(
+            loaded.source_url.as_deref(),
+            Some("https://example.com/test")
+        );
+
+        // Old files which have no license
+        let json = serde_json::to_string(&Manifest::new("Old", "Tester")).unwrap();
+        assert!(!json.contains("license"));
+        let loaded: Manifest = serde_json::from_str(&json).unwrap();
+        assert!(loaded.license.is_none());
+        assert!(loaded.source_url.is_none());
+    }
+
+    #[test]
+    fn insert_sound() {
+        let mut manifest = Manifest::new("Test", "Tester");
+        assert!(manifest.insert_sound(3, "kick.wav", 36).is_none());
+        assert!(manifest.insert_sound(7, "snare.wav", 38).is_none());
+        assert!(manifest.insert_sound(5, "hihat.wav", 42).is_none());
+        assert!(manifest.insert_sound(0, "clap.wav", 39).is_none());
+        let ids: Vec<u16> = manifest.sounds.iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![0, 3, 5, 7]);
+
+        // Replace
+        let old = manifest.insert_sound(5, "open_hihat.wav", 46).unwrap();
+        assert_eq!(old.path, "hihat.wav");
+        assert_eq!(manifest.sounds.len(), 4);
+        assert_eq!(manifest.get_sound_path(5), Some("open_hihat.wav"));
+    }
+
+    #[test]
+    fn remove_sound() {
+        let mut manifest = Manifest::new("Test", "Tester");
+        manifest.push_sound("kick.wav", 36);
+        manifest.push_sound("snare.wav", 38);
+        let soundmap = SoundMap::from_notes([(0, 0, 0)]);
+
+        assert!(manifest.is_sound_referenced(0, &soundmap));
+        assert!(!manifest.is_sound_referenced(1, &soundmap));
+
+        let removed = manifest.remove_sound(1).unwrap();
+        assert_eq!(removed.path, "snare.wav");
+        assert_eq!(manifest.sounds.len(), 1);
+        assert!(manifest.remove_sound(1).is_none());
+    }
+
+    #[test]
+    fn push_sound_when_ids_are_used() {
+        let mut manifest = Manifest::new("Test", "Tester");
+        assert_eq!(manifest.push_sound("kick.wav", 36), Some(0));
+        manifest.sounds = (0..=u16::MAX)
+            .map(|id| Sound::new(id, "kick.wav", 36))
+            .collect();
+        assert_eq!(manifest.push_sound("snare.wav", 38), None);
+        assert_eq!(manifest.sounds.len(), usize::from(u16::MAX) + 1);
+    }
+
+    #[test]
+    fn percussion_sounds() {
+        let mut manifest = Manifest::new("Test", "Tester");
+        manifest.push_sound("kick.wav", 36);
+        manifest.push_sound("piano_c4.wav", 60);
+        manifest.push_sound("untagged_snare.wav", 38);
+
+        let mut soundmap = SoundMap::new();
+        soundmap.set_note_track(0, "Kick", Instrument::Kick);
+        soundmap.set_note_track(1, "Piano", Instrument::Pno);
+        soundmap.insert_note(0, 0, 0);
+        soundmap.insert_note(1, 0, 1);
+        soundmap.insert_note(2, 192, 5);
+
+        assert!(manifest.sounds[0].is_percussion(&soundmap));
+        assert!(!manifest.sounds[1].is_percussion(&soundmap));
+        // By pitch
+        assert!(manifest.sounds[2].is_percussion(&soundmap));
+
+        let percussion = manifest.percussion_sounds(&soundmap);
+        assert_eq!(percussion.len(), 2);
+        assert_eq!(percussion[0].path, "kick.wav");
+    }
+
+    #[test]
+    fn apply_gm_drummap() {
+        let mut manifest = Manifest::new("Test", "Tester");
+        manifest.push_sound("kick.wav", 35);
+        manifest.push_sound("tom.wav", 44);
+        manifest.push_sound("piano.wav", 35);
+        manifest.push_sound("snare.wav", 38);
+
+        let mut soundmap = SoundMap::from_notes([(0, 0, 0), (1, 0, 1), (2, 0, 2), (3, 0, 3)]);
+        soundmap.set_note_track(0, "Kick", Instrument::Kick);
+        soundmap.set_note_track(1, "Tom", Instrument::Tom);
+        soundmap.set_note_track(2, "Piano", Instrument::Pno);
+        soundmap.set_note_track(3, "Snare", Instrument::Snare);
+
+        let remaps = manifest.apply_gm_drummap(&soundmap);
+        assert_eq!(
+            remaps,
+            vec![
+                PitchRemap {
+                    sound_id: 0,
+                    from: 35,
+                    to: 36
+                },
+                // Same distance to 43 and 45
+                PitchRemap {
+                    sound_id: 1,
+                    from: 44,
+                    to: 43
+                },
+            ]
+        );
+        assert_eq!(manifest.sounds[0].pitch, 36);
+        assert_eq!(manifest.sounds[2].pitch, 35);
+        assert!(manifest.apply_gm_drummap(&soundmap).is_empty());
+    }
+
+    #[test]
+    fn validate_extensions() {
+        let mut manifest = Manifest::new("Test", "Tester");
+        manifest.push_sound("kick.wav", 36);
+        manifest.push_sound("snare.OGG", 38);
+
+        let soundmap = SoundMap::new().with_audio_format("ogg");
+        let mismatches = manifest.validate_extensions(&soundmap);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].path, "kick.wav");
+        assert_eq!(mismatches[0].expected, &["ogg", "oga"]);
+
+        let soundmap = SoundMap::new().with_audio_format("wav");
+        assert_eq!(manifest.validate_extensions(&soundmap)[0].path, "snare.OGG");
+    }
+
+    #[test]
+    fn sound_loop_points() {
+        let sound = Sound::new(0, "pad.wav", 60)
+            .with_loop_points(1000, 5000)
+            .unwrap();
+
+        let json = serde_json::to_string(&sound).unwrap();
+        let loaded: Sound = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.loop_start, Some(1000));
+        assert_eq!(loaded.loop_end, Some(5000));
+        assert!(loaded.has_valid_loop_points());
+
+        // Files without loop points
+        let loaded: Sound = serde_json::from_str(r#"{"id":0,"path":"a.wav","pitch":60}"#).unwrap();
+        assert_eq!(loaded.loop_start, None);
+        assert!(loaded.has_valid_loop_points());
+
+        assert!(matches!(
+            Sound::new(0, "pad.wav", 60).with_loop_points(5000, 1000),
+            Err(SmapError::InvalidLoopPoints { .. })
+        ));
+    }
+
+    #[test]
+    fn sound_cents() {
+        let mut manifest = Manifest::new("Test", "Tester");
+        manifest.push_sound("lead.wav", 60);
+        manifest.sounds[0].cents = Some(50);
+
+        let json = serde_json::to_string(&manifest).unwrap();
+        let loaded: Manifest = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.sounds[0].cents, Some(50));
+        assert!(loaded.sounds[0].has_valid_cents());
+
+        // Files without cents
+        let loaded: Sound = serde_json::from_str(r#"{"id":0,"path":"a.wav","pitch":60}"#).unwrap();
+        assert_eq!(loaded.cents, None);
+
+        manifest.sounds[0].cents = Some(150);
+        assert!(!manifest.sounds[0].has_valid_cents());
+    }
+}
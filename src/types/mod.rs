@@ -13,3 +13,39 @@ pub mod prelude {
 }
 
 pub use crate::types::prelude::*;
+
+/// Get the smallest id which is not in `existing`. The order of `existing` doesn't matter.
+///
+/// If every id is used, it returns `None`.
+pub(crate) fn next_free_id(existing: &[u16]) -> Option<u16> {
+    let mut ids = existing.to_vec();
+    ids.sort_unstable();
+    ids.dedup();
+
+    // The first id which is not same as its index, after sorting
+    let free = ids
+        .iter()
+        .enumerate()
+        .find(|(index, id)| *index != usize::from(**id))
+        .map_or(ids.len(), |(index, _)| index);
+    u16::try_from(free).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_next_free_id() {
+        assert_eq!(next_free_id(&[]), Some(0));
+        assert_eq!(next_free_id(&[2, 0, 5]), Some(1));
+        assert_eq!(next_free_id(&[0, 1, 2]), Some(3));
+        assert_eq!(next_free_id(&[3, 1, 0, 1]), Some(2));
+        assert_eq!(next_free_id(&[1, 2]), Some(0));
+
+        // Every id is used
+        let full: Vec<u16> = (0..=u16::MAX).rev().collect();
+        assert_eq!(next_free_id(&full), None);
+        assert_eq!(next_free_id(&full[1..]), Some(u16::MAX));
+    }
+}
@@ -1,14 +1,23 @@
 //! This module contains the definition of related to sound stuff.x
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashSet;
+
+use crate::error::SmapError;
+use crate::types::next_free_id;
 
 /// This `const` defines the recommended note tick.
 /// This number is used many digital music software.
 /// If the note tick doesn't match the recommended note tick, it can't guarantee to compatibility with other software.
 const RECOMMENDED_NOTE_TICK: u16 = 192;
 
+/// Get the greatest common divisor. `gcd(0, x)` is `x`.
+pub(crate) fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
 /// Defines a note in a soundmap.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Note {
     /// The ID of the note.
@@ -26,7 +35,7 @@ pub struct Note {
 }
 
 /// Defines a BPM set or change in a soundmap.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Bpm {
     /// A BPM value
     pub value: f64,
@@ -51,8 +60,85 @@ impl Bpm {
     }
 }
 
+/// A summary of BPM in a soundmap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BpmSummary {
+    /// The lowest BPM
+    pub min: f64,
+
+    /// The highest BPM
+    pub max: f64,
+
+    /// An average of BPM which weighted by the duration of each tempo segment in ticks.
+    pub average_time_weighted: f64,
+}
+
+/// A tempo or meter change in a tempo map. (See `SoundMap::tempo_map`)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TempoMapEntry {
+    /// Same as `Note.time`.
+    pub time: u32,
+
+    /// A bar number, starting from 1.
+    pub bar: u32,
+
+    /// A beat in the bar, starting from 1. It is fractional if the change is not on a beat.
+    pub beat: f64,
+
+    /// Seconds from the start of the soundmap
+    pub seconds: f64,
+
+    /// A new BPM, if BPM changes here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bpm: Option<f64>,
+
+    /// A new beat per bar, if the meter changes here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub beat_per_bar: Option<u8>,
+}
+
+/// Differences between two soundmaps. (See `SoundMap::diff`)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SoundMapDiff {
+    /// Notes which are only in the other soundmap
+    pub added: Vec<Note>,
+
+    /// Notes which are only in this soundmap
+    pub removed: Vec<Note>,
+
+    /// Notes which are matched, but time, track or sound is changed. (Old, New)
+    pub moved: Vec<(Note, Note)>,
+
+    /// BPM changes which are only in the other soundmap
+    pub bpm_added: Vec<Bpm>,
+
+    /// BPM changes which are only in this soundmap
+    pub bpm_removed: Vec<Bpm>,
+
+    /// Meter changes which are only in the other soundmap
+    pub meter_added: Vec<BeatPerBar>,
+
+    /// Meter changes which are only in this soundmap
+    pub meter_removed: Vec<BeatPerBar>,
+}
+
+impl SoundMapDiff {
+    /// Whether there are no differences.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Split `new` and `old` into items which are only in one of them.
+fn list_diff<T: Clone + PartialEq>(old: &[T], new: &[T]) -> (Vec<T>, Vec<T>) {
+    let added = new.iter().filter(|n| !old.contains(n)).cloned().collect();
+    let removed = old.iter().filter(|o| !new.contains(o)).cloned().collect();
+    (added, removed)
+}
+
 /// Defines a beat-per-bar setting in a soundmap.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct BeatPerBar {
     /// The value of the beat-per-bar setting.
     /// If the value is `4`, it means 4 beats per a bar. (similar as 4/4 time)
@@ -76,9 +162,10 @@ impl BeatPerBar {
 }
 
 /// Defines an instrument
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Instrument {
     /// Etc.
+    #[default]
     SomeElse,
 
     /// Kick Drums
@@ -127,14 +214,141 @@ pub enum Instrument {
     Vox,
 }
 
-impl Default for Instrument {
-    fn default() -> Self {
-        Self::SomeElse
+impl Instrument {
+    /// Whether the instrument is a drum or percussion.
+    pub fn is_percussion(&self) -> bool {
+        matches!(
+            self,
+            Self::Kick
+                | Self::Snare
+                | Self::HiHat
+                | Self::Tom
+                | Self::CrashCym
+                | Self::RideCym
+                | Self::Clap
+        )
+    }
+
+    /// Get pitches of the instrument in MIDI GM Drummap. (ex. `Kick` is 36: Bass Drum 1)
+    ///
+    /// It returns an empty list if the instrument is not percussion.
+    pub fn gm_drum_pitches(&self) -> &'static [u8] {
+        match self {
+            Self::Kick => &[36],
+            Self::Snare => &[38, 40],
+            Self::HiHat => &[42, 44, 46],
+            Self::Tom => &[41, 43, 45, 47, 48, 50],
+            Self::CrashCym => &[49, 57],
+            Self::RideCym => &[51, 59],
+            Self::Clap => &[39],
+            _ => &[],
+        }
+    }
+
+    /// Find an instrument from a loose name. (ex. `electric guitar`, `E-Gui`, `synth`)
+    ///
+    /// Case, spaces and separators are ignored. It returns `None` for unknown names.
+    pub fn from_loose_str(name: &str) -> Option<Self> {
+        let normalized: String = name
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-' && *c != '_')
+            .flat_map(char::to_lowercase)
+            .collect();
+
+        let instrument = match normalized.as_str() {
+            "someelse" | "etc" | "other" => Self::SomeElse,
+            "kick" | "kickdrum" | "bassdrum" | "bd" => Self::Kick,
+            "snare" | "snaredrum" | "sd" => Self::Snare,
+            "hihat" | "hh" | "hat" => Self::HiHat,
+            "tom" | "toms" | "tomdrum" => Self::Tom,
+            "crash" | "crashcym" | "crashcymbal" => Self::CrashCym,
+            "ride" | "ridecym" | "ridecymbal" => Self::RideCym,
+            "clap" | "handclap" => Self::Clap,
+            "pno" | "piano" => Self::Pno,
+            "agui" | "guitar" | "acousticguitar" => Self::AGui,
+            "egui" | "electricguitar" => Self::EGui,
+            "bgui" | "bass" | "bassguitar" => Self::BGui,
+            "ebgui" | "electricbass" | "electricbassguitar" => Self::EBGui,
+            "kbd" | "keyboard" => Self::Kbd,
+            "syn" | "synth" | "synthesizer" => Self::Syn,
+            "vox" | "voice" | "vocal" | "vocals" => Self::Vox,
+            _ => return None,
+        };
+        Some(instrument)
+    }
+}
+
+/// A numeric form of `Instrument`, for clients which expect integers. It is serialized as an
+/// integer id, instead of the name of the variant.
+///
+/// ## A list of ids
+/// |Id|Instrument|
+/// |---|---|
+/// |0|SomeElse|
+/// |1|Kick|
+/// |2|Snare|
+/// |3|HiHat|
+/// |4|Tom|
+/// |5|CrashCym|
+/// |6|RideCym|
+/// |7|Clap|
+/// |8|Pno|
+/// |9|AGui|
+/// |10|EGui|
+/// |11|BGui|
+/// |12|EBGui|
+/// |13|Kbd|
+/// |14|Syn|
+/// |15|Vox|
+///
+/// Ids are stable, and new instruments get new ids.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct NumericInstrument(pub Instrument);
+
+/// Instruments in the order of ids. (See `NumericInstrument`)
+const INSTRUMENT_IDS: [Instrument; 16] = [
+    Instrument::SomeElse,
+    Instrument::Kick,
+    Instrument::Snare,
+    Instrument::HiHat,
+    Instrument::Tom,
+    Instrument::CrashCym,
+    Instrument::RideCym,
+    Instrument::Clap,
+    Instrument::Pno,
+    Instrument::AGui,
+    Instrument::EGui,
+    Instrument::BGui,
+    Instrument::EBGui,
+    Instrument::Kbd,
+    Instrument::Syn,
+    Instrument::Vox,
+];
+
+impl From<NumericInstrument> for u8 {
+    fn from(instrument: NumericInstrument) -> Self {
+        INSTRUMENT_IDS
+            .iter()
+            .position(|i| *i == instrument.0)
+            .unwrap() as u8
+    }
+}
+
+impl TryFrom<u8> for NumericInstrument {
+    type Error = String;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        INSTRUMENT_IDS
+            .get(usize::from(id))
+            .cloned()
+            .map(Self)
+            .ok_or_else(|| format!("Unknown instrument id {id}"))
     }
 }
 
 /// Defines a track
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TrackTag {
     /// The id of the track.
     pub id: u16,
@@ -146,14 +360,24 @@ pub struct TrackTag {
     pub instrument: Instrument,
 }
 
-impl Default for TrackTag {
-    fn default() -> Self {
+impl TrackTag {
+    pub fn new(id: u16, name: &str, instrument: Instrument) -> Self {
         Self {
-            id: 0,
-            name: String::new(),
-            instrument: Instrument::default(),
+            id,
+            name: name.to_string(),
+            instrument,
         }
     }
+
+    /// Same as `new`, but the instrument is parsed by `Instrument::from_loose_str`. Unknown
+    /// names are `Instrument::SomeElse`.
+    pub fn with_instrument_str(id: u16, name: &str, instrument: &str) -> Self {
+        Self::new(
+            id,
+            name,
+            Instrument::from_loose_str(instrument).unwrap_or_default(),
+        )
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -216,11 +440,56 @@ impl Default for SoundMap {
     }
 }
 
+/// A `SoundMap` which is validated and normalized on deserialize.
+///
+/// See `SoundMap::validate_and_normalize`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(transparent)]
+pub struct ValidatedSoundMap(pub SoundMap);
+
+impl ValidatedSoundMap {
+    pub fn into_inner(self) -> SoundMap {
+        self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for ValidatedSoundMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut soundmap = SoundMap::deserialize(deserializer)?;
+        soundmap
+            .validate_and_normalize()
+            .map_err(serde::de::Error::custom)?;
+        Ok(Self(soundmap))
+    }
+}
+
 impl SoundMap {
     pub fn new() -> Self {
         Self::default()
     }
 
+    /// Make a soundmap from `(sound_id, time, track)` of notes. Note ids are `0..n` in order,
+    /// and others are default.
+    ///
+    /// # Panics
+    ///
+    /// It panics if there are more notes than ids. (`u16::MAX + 1`)
+    pub fn from_notes(notes: impl IntoIterator<Item = (u16, u32, u16)>) -> Self {
+        Self {
+            notes: notes
+                .into_iter()
+                .enumerate()
+                .map(|(id, (sound_id, time, track))| Note {
+                    id: u16::try_from(id).expect("Too many notes for note ids"),
+                    sound_id,
+                    time,
+                    track,
+                })
+                .collect(),
+            ..Self::default()
+        }
+    }
+
     pub fn with_audio_format(mut self, audio_format: &str) -> Self {
         self.audio_format = audio_format.to_string();
         self
@@ -260,49 +529,1205 @@ impl SoundMap {
                 return;
             }
         }
-        self.track_tags.push(TrackTag {
+        self.track_tags.push(TrackTag::new(id, name, inst));
+    }
+
+    /// Get the note with `id`.
+    pub fn get_note(&self, id: u16) -> Option<&Note> {
+        self.notes.iter().find(|n| n.id == id)
+    }
+
+    /// Get the note with `id` as mutable.
+    pub fn get_note_mut(&mut self, id: u16) -> Option<&mut Note> {
+        self.notes.iter_mut().find(|n| n.id == id)
+    }
+
+    /// Remove the note with `id`, and return it. Ids of other notes are not changed.
+    pub fn remove_note(&mut self, id: u16) -> Option<Note> {
+        let index = self.notes.iter().position(|n| n.id == id)?;
+        Some(self.notes.remove(index))
+    }
+
+    /// Add a note with the smallest unused id, and return the id.
+    ///
+    /// If every id is used, nothing is added and it returns `None`.
+    pub fn insert_note(&mut self, sound_id: u16, time: u32, track: u16) -> Option<u16> {
+        let ids: Vec<u16> = self.notes.iter().map(|n| n.id).collect();
+        let id = next_free_id(&ids)?;
+        self.notes.push(Note {
             id,
-            name: name.to_string(),
-            instrument: inst,
+            sound_id,
+            time,
+            track,
         });
+        Some(id)
     }
 
-    pub fn insert_note(&mut self, sound_id: u16, time: u32, track: u16) {
-        let mut ids: Vec<u16> = Vec::new();
+    /// Check invariants of the soundmap, and normalize it.
+    ///
+    /// BPM and beat-per-bar lists are sorted by time. It fails if a BPM is not positive, a
+    /// beat-per-bar is 0, or note ids are duplicated.
+    pub fn validate_and_normalize(&mut self) -> Result<(), SmapError> {
+        if let Some(bpm) = self
+            .bpm
+            .iter()
+            .find(|b| !b.value.is_finite() || b.value <= 0.0)
+        {
+            return Err(SmapError::InvalidSoundmap(format!(
+                "Invalid BPM {} at {}",
+                bpm.value, bpm.time
+            )));
+        }
+        if let Some(meter) = self.beat_per_bar.iter().find(|b| b.value == 0) {
+            return Err(SmapError::InvalidSoundmap(format!(
+                "Invalid beat per bar 0 at {}",
+                meter.time
+            )));
+        }
 
-        for n in &self.notes {
-            ids.push(n.id);
+        let mut ids: Vec<u16> = self.notes.iter().map(|n| n.id).collect();
+        ids.sort();
+        if let Some(pair) = ids.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(SmapError::InvalidSoundmap(format!(
+                "Duplicated note id {}",
+                pair[0]
+            )));
         }
 
-        if self.notes.len() == 0 {
-            self.notes.push(Note {
-                id: 0,
-                sound_id,
-                time,
-                track,
-            });
+        self.bpm.sort_by_key(|b| b.time);
+        self.beat_per_bar.sort_by_key(|b| b.time);
+
+        Ok(())
+    }
+
+    /// Insert a default BPM (120) at tick 0 if there are no BPMs. It returns whether it is
+    /// inserted.
+    ///
+    /// `load_smap_dir` does it for validated soundmaps, and `check_smap` warns about it.
+    pub fn ensure_tempo(&mut self) -> bool {
+        if !self.bpm.is_empty() {
+            return false;
+        }
+        self.bpm.push(Bpm::default());
+        true
+    }
+
+    /// Sort every list into the canonical order, for a clean save.
+    ///
+    /// Notes and tracks are sorted by id, and BPM and meter changes by time. If changes are on
+    /// the same time, the later one is kept. BPM changes to the same value as before are
+    /// removed, but meter changes are kept, because they restart the bar. It is idempotent.
+    pub fn normalize(&mut self) {
+        self.notes.sort_by_key(|n| n.id);
+        self.track_tags.sort_by_key(|t| t.id);
+
+        self.bpm.sort_by_key(|b| b.time);
+        self.bpm.reverse();
+        self.bpm.dedup_by_key(|b| b.time);
+        self.bpm.reverse();
+        self.bpm.dedup_by(|b, prev| b.value == prev.value);
+
+        self.beat_per_bar.sort_by_key(|b| b.time);
+        self.beat_per_bar.reverse();
+        self.beat_per_bar.dedup_by_key(|b| b.time);
+        self.beat_per_bar.reverse();
+    }
+
+    /// Sort notes by `(time, track)`, for playback or export. Note ids are not changed.
+    ///
+    /// The sort is stable, so notes on same time and track keep their order. Unlike
+    /// `normalize`, notes are not sorted by id.
+    pub fn sort_notes(&mut self) {
+        self.notes.sort_by_key(|n| (n.time, n.track));
+    }
+
+    /// Whether notes are sorted by `(time, track)`. (See `sort_notes`)
+    pub fn is_sorted(&self) -> bool {
+        self.notes.is_sorted_by_key(|n| (n.time, n.track))
+    }
+
+    /// Sort notes by `(time, track)`, and BPM and meter changes by time.
+    ///
+    /// Same as `sort_notes`, nothing is removed or changed.
+    pub fn sort_all(&mut self) {
+        self.sort_notes();
+        self.bpm.sort_by_key(|b| b.time);
+        self.beat_per_bar.sort_by_key(|b| b.time);
+    }
+
+    /// Snap BPM changes to the nearest grid tick.
+    ///
+    /// `subdivision` is a count of grid cells per beat. (ex. `4` means 16th notes in 4/4 time)
+    /// If BPM changes are snapped to same tick, the later one is kept.
+    pub fn align_bpm_to_grid(&mut self, subdivision: u16) {
+        if subdivision == 0 {
+            return;
+        }
+        let step = u32::from((self.note_tick / subdivision).max(1));
+
+        self.bpm.sort_by_key(|b| b.time);
+        // Near the end of u32, snap down to the last grid tick
+        let last_grid = u32::MAX / step * step;
+        for bpm in &mut self.bpm {
+            let snapped =
+                (u64::from(bpm.time) + u64::from(step / 2)) / u64::from(step) * u64::from(step);
+            bpm.time = u32::try_from(snapped).unwrap_or(last_grid);
+        }
+
+        // Keep the later one
+        self.bpm.reverse();
+        self.bpm.dedup_by_key(|b| b.time);
+        self.bpm.reverse();
+    }
+
+    /// Fix notes whose time is after `max_time`. They are removed if `remove` is set, or moved
+    /// to `max_time` if not. It returns the count of the notes.
+    pub fn clamp_notes_to(&mut self, max_time: u32, remove: bool) -> usize {
+        let count = self.notes.iter().filter(|n| n.time > max_time).count();
+        if remove {
+            self.notes.retain(|n| n.time <= max_time);
         } else {
-            for (index, note_id) in ids.iter().enumerate() {
-                // If missing number (0:0, 1:1, '2:3', 3:4 ...)
-                if index != *note_id as usize {
-                    self.notes.push(Note {
-                        id: index as u16,
-                        sound_id,
-                        time,
-                        track,
-                    });
-                    break;
+            for note in self.notes.iter_mut().filter(|n| n.time > max_time) {
+                note.time = max_time;
+            }
+        }
+        count
+    }
+
+    /// Keep only notes in `start..end`, and move them so `start` is tick 0. (ex. excerpts)
+    ///
+    /// BPM and beat-per-bar changes are trimmed too. The BPM and beat-per-bar at `start` (See
+    /// `bpm_at` and `beat_per_bar_at`) are kept at tick 0, unless the list is empty. If `end` is
+    /// not after `start`, all notes are removed.
+    pub fn trim(&mut self, start: u32, end: u32) {
+        self.notes.retain(|n| n.time >= start && n.time < end);
+        for note in &mut self.notes {
+            note.time -= start;
+        }
+
+        self.bpm.sort_by_key(|b| b.time);
+        if !self.bpm.is_empty() {
+            let active = self.bpm_at(start);
+            self.bpm.retain(|b| b.time > start && b.time < end);
+            for bpm in &mut self.bpm {
+                bpm.time -= start;
+            }
+            self.bpm.insert(0, Bpm::new(active, 0));
+        }
+
+        self.beat_per_bar.sort_by_key(|b| b.time);
+        if !self.beat_per_bar.is_empty() {
+            let active = self.beat_per_bar_at(start);
+            self.beat_per_bar.retain(|b| b.time > start && b.time < end);
+            for meter in &mut self.beat_per_bar {
+                meter.time -= start;
+            }
+            self.beat_per_bar.insert(0, BeatPerBar::new(active, 0));
+        }
+    }
+
+    /// Swap track ids `a` and `b` of `TrackTag`s and notes, so notes on `a` are on `b` and vice
+    /// versa.
+    ///
+    /// It does nothing if either track has no tag and no notes.
+    pub fn swap_tracks(&mut self, a: u16, b: u16) {
+        let exists = |id: u16| {
+            self.track_tags.iter().any(|t| t.id == id) || self.notes.iter().any(|n| n.track == id)
+        };
+        if a == b || !exists(a) || !exists(b) {
+            return;
+        }
+
+        let swap = |id: &mut u16| {
+            if *id == a {
+                *id = b;
+            } else if *id == b {
+                *id = a;
+            }
+        };
+        self.track_tags.iter_mut().for_each(|t| swap(&mut t.id));
+        self.notes.iter_mut().for_each(|n| swap(&mut n.track));
+    }
+
+    /// Get track ids which are used by notes, but have no `TrackTag`.
+    pub fn untagged_tracks(&self) -> Vec<u16> {
+        let mut tracks: Vec<u16> = self
+            .notes
+            .iter()
+            .map(|n| n.track)
+            .filter(|track| !self.track_tags.iter().any(|tag| tag.id == *track))
+            .collect();
+        tracks.sort();
+        tracks.dedup();
+        tracks
+    }
+
+    /// Get distinct instruments of tracks which have notes, in the order of first appearance in
+    /// notes. (ex. for legends)
+    ///
+    /// Tracks without tags are skipped.
+    pub fn used_instruments(&self) -> Vec<Instrument> {
+        let mut instruments = Vec::new();
+        let mut seen = HashSet::new();
+        for note in &self.notes {
+            if let Some(tag) = self.track_tags.iter().find(|t| t.id == note.track)
+                && seen.insert(&tag.instrument)
+            {
+                instruments.push(tag.instrument.clone());
+            }
+        }
+        instruments
+    }
+
+    /// Add default `TrackTag`s for tracks which are used by notes, but have no tag.
+    pub fn add_missing_track_tags(&mut self) {
+        for id in self.untagged_tracks() {
+            self.track_tags.push(TrackTag {
+                id,
+                ..TrackTag::default()
+            });
+        }
+    }
+
+    /// Get start ticks of each measure (bar), from tick 0 to the last note.
+    ///
+    /// It follows beat-per-bar changes. A change starts a new measure, even if it isn't on the
+    /// boundary of measures.
+    pub fn measure_boundaries(&self) -> Vec<u32> {
+        let end = self.notes.iter().map(|n| n.time).max().unwrap_or(0);
+        self.measure_boundaries_until(end)
+    }
+
+    /// Same as `measure_boundaries`, but until `end` tick.
+    pub(crate) fn measure_boundaries_until(&self, end: u32) -> Vec<u32> {
+        let mut meters: Vec<&BeatPerBar> = self.beat_per_bar.iter().collect();
+        meters.sort_by_key(|b| b.time);
+        let mut beats = meters
+            .first()
+            .map_or(BeatPerBar::default().value, |b| b.value);
+
+        let mut boundaries = Vec::new();
+        let mut change_index = 0;
+        let mut time = 0;
+        loop {
+            while let Some(change) = meters.get(change_index).filter(|b| b.time <= time) {
+                beats = change.value;
+                change_index += 1;
+            }
+            boundaries.push(time);
+
+            let bar_ticks = u32::from(beats) * u32::from(self.note_tick);
+            if bar_ticks == 0 {
+                break;
+            }
+            // `None` if the next bar starts after u32::MAX
+            let mut next = time.checked_add(bar_ticks);
+            if let Some(change) = meters
+                .get(change_index)
+                .filter(|b| next.is_none_or(|next| b.time < next))
+            {
+                next = Some(change.time);
+            }
+
+            match next {
+                Some(next) if next <= end => time = next,
+                _ => break,
+            }
+        }
+
+        boundaries
+    }
+
+    /// Convert a tick to seconds, following BPM changes before the tick. (ex. for playback of
+    /// `Note.time`)
+    ///
+    /// `bpm` doesn't need to be sorted, because it is sorted by `time` internally. The first BPM
+    /// is used from tick 0, even if it starts later. If the list is empty, 120 BPM is used, and
+    /// if `note_tick` is `0`, it returns `0.0`.
+    pub fn tick_to_seconds(&self, tick: u32) -> f64 {
+        if self.note_tick == 0 {
+            return 0.0;
+        }
+        let seconds_per_tick = |bpm: f64| 60.0 / (bpm * self.note_tick as f64);
+
+        let mut bpm: Vec<&Bpm> = self.bpm.iter().collect();
+        bpm.sort_by_key(|b| b.time);
+        let default = Bpm::default();
+        let mut current = *bpm.first().unwrap_or(&&default);
+
+        let mut seconds = 0.0;
+        let mut current_time = 0;
+        for change in bpm {
+            if change.time >= tick {
+                break;
+            }
+            seconds += (change.time - current_time) as f64 * seconds_per_tick(current.value);
+            current_time = change.time;
+            current = change;
+        }
+
+        seconds + (tick - current_time) as f64 * seconds_per_tick(current.value)
+    }
+
+    /// Get the BPM value on the tick.
+    ///
+    /// It is the last BPM change on or before the tick. Same as `tick_to_seconds`, the first
+    /// BPM is used before it starts, and 120 BPM is used if the list is empty.
+    pub fn bpm_at(&self, tick: u32) -> f64 {
+        self.bpm
+            .iter()
+            .filter(|b| b.time <= tick)
+            .max_by_key(|b| b.time)
+            .or_else(|| self.bpm.iter().min_by_key(|b| b.time))
+            .map_or(Bpm::default().value, |b| b.value)
+    }
+
+    /// Get the beats per bar on the tick. (See `bpm_at`)
+    ///
+    /// The first meter is used before it starts, and the default meter is used if the list is
+    /// empty.
+    pub fn beat_per_bar_at(&self, tick: u32) -> u8 {
+        self.beat_per_bar
+            .iter()
+            .filter(|b| b.time <= tick)
+            .max_by_key(|b| b.time)
+            .or_else(|| self.beat_per_bar.iter().min_by_key(|b| b.time))
+            .map_or(BeatPerBar::default().value, |b| b.value)
+    }
+
+    /// Get the BPM value on the last note, for an outro. (See `bpm_at`)
+    ///
+    /// If there are no notes, it returns the BPM on tick 0.
+    pub fn final_bpm(&self) -> f64 {
+        self.bpm_at(self.notes.iter().map(|n| n.time).max().unwrap_or(0))
+    }
+
+    /// Group notes which are played at the same time, across tracks.
+    ///
+    /// Notes in a group are within `tolerance` ticks from the first note of the group. Groups
+    /// are sorted by time, and a note without others forms a group of one note.
+    pub fn chords(&self, tolerance: u32) -> Vec<Vec<&Note>> {
+        let mut notes: Vec<&Note> = self.notes.iter().collect();
+        notes.sort_by_key(|n| n.time);
+
+        let mut chords: Vec<Vec<&Note>> = Vec::new();
+        for note in notes {
+            match chords.last_mut() {
+                Some(chord) if note.time - chord[0].time <= tolerance => chord.push(note),
+                _ => chords.push(vec![note]),
+            }
+        }
+        chords
+    }
+
+    /// Get the peak count of notes which sound at the same time, when each note sustains for
+    /// `sustain_ticks`.
+    ///
+    /// A note sounds from its time until `time + sustain_ticks`, exclusive. `0` is treated as 1
+    /// tick, so only notes on the same time overlap.
+    pub fn max_polyphony(&self, sustain_ticks: u32) -> usize {
+        let sustain = sustain_ticks.max(1);
+
+        // Ends come before starts on the same time
+        let mut events: Vec<(u32, bool)> = self
+            .notes
+            .iter()
+            .flat_map(|n| [(n.time, true), (n.time.saturating_add(sustain), false)])
+            .collect();
+        events.sort_unstable();
+
+        let mut current = 0;
+        let mut max = 0;
+        for (_, is_start) in events {
+            if is_start {
+                current += 1;
+                max = max.max(current);
+            } else {
+                current -= 1;
+            }
+        }
+        max
+    }
+
+    /// Find notes which look like uninitialized notes. (sound `0`, time `0` and track `0`)
+    ///
+    /// A note like that can be a real note, so they are returned only if there are 2 or more
+    /// of them. Otherwise, it returns an empty list.
+    pub fn suspicious_default_notes(&self) -> Vec<&Note> {
+        let notes: Vec<&Note> = self
+            .notes
+            .iter()
+            .filter(|n| n.sound_id == 0 && n.time == 0 && n.track == 0)
+            .collect();
+        if notes.len() < 2 {
+            return Vec::new();
+        }
+        notes
+    }
+
+    /// Find gaps between notes which are longer than `min_ticks`. (ex. for unintended pauses)
+    ///
+    /// It returns `(start, end)` ticks of each gap, where `start` and `end` are times of
+    /// consecutive notes in time order. Gaps before the first note are not included.
+    pub fn silence_gaps(&self, min_ticks: u32) -> Vec<(u32, u32)> {
+        let mut times: Vec<u32> = self.notes.iter().map(|n| n.time).collect();
+        times.sort_unstable();
+
+        times
+            .windows(2)
+            .filter(|pair| pair[1] - pair[0] > min_ticks)
+            .map(|pair| (pair[0], pair[1]))
+            .collect()
+    }
+
+    /// Get times of every beat in milliseconds, from 0 to the last note. It is useful for visual
+    /// cues. (ex. lighting)
+    ///
+    /// Beats restart from each bar start, so they are aligned with `bar_pulses_ms`.
+    pub fn beat_pulses_ms(&self) -> Vec<f64> {
+        let end = self.notes.iter().map(|n| n.time).max().unwrap_or(0);
+        let boundaries = self.measure_boundaries_until(end);
+        let beat = u32::from(self.note_tick).max(1);
+
+        let mut beats = Vec::new();
+        for (index, start) in boundaries.iter().enumerate() {
+            // Inclusive to `end`, so a note on u32::MAX doesn't overflow
+            let next = boundaries.get(index + 1).copied();
+            beats.extend(
+                (*start..=end)
+                    .step_by(beat as usize)
+                    .take_while(|tick| next.is_none_or(|next| *tick < next))
+                    .map(|tick| self.tick_to_seconds(tick) * 1000.0),
+            );
+        }
+        beats
+    }
+
+    /// Get times of every bar start in milliseconds, from 0 to the last note.
+    /// (See `measure_boundaries`)
+    pub fn bar_pulses_ms(&self) -> Vec<f64> {
+        self.measure_boundaries()
+            .into_iter()
+            .map(|tick| self.tick_to_seconds(tick) * 1000.0)
+            .collect()
+    }
+
+    /// Get the greatest common divisor of note times. It is the coarsest grid which can
+    /// represent every note exactly, so it is useful for choosing `note_tick`.
+    ///
+    /// Notes on time `0` are on every grid. If there are no notes after `0`, it returns `0`.
+    pub fn time_gcd(&self) -> u32 {
+        self.notes.iter().fold(0, |acc, n| gcd(acc, n.time))
+    }
+
+    /// Compare notes, BPM and meter changes with `other`, a newer version of this soundmap.
+    ///
+    /// Notes are matched by id first. Notes which aren't matched are matched again by
+    /// `(time, track)`, so renumbered notes are not reported.
+    pub fn diff(&self, other: &SoundMap) -> SoundMapDiff {
+        let mut diff = SoundMapDiff::default();
+
+        let mut unmatched_old = Vec::new();
+        let mut unmatched_new: Vec<&Note> = other
+            .notes
+            .iter()
+            .filter(|n| !self.notes.iter().any(|o| o.id == n.id))
+            .collect();
+        for old in &self.notes {
+            match other.notes.iter().find(|n| n.id == old.id) {
+                Some(new) => {
+                    if (old.time, old.track, old.sound_id) != (new.time, new.track, new.sound_id) {
+                        diff.moved.push((old.clone(), new.clone()));
+                    }
                 }
-                // If last index
-                else if index == (ids.len() - 1) {
-                    self.notes.push(Note {
-                        id: (index as u16) + 1,
-                        sound_id,
-                        time,
-                        track,
-                    });
+                None => unmatched_old.push(old),
+            }
+        }
+
+        // Fallback by `(time, track)`
+        for old in unmatched_old {
+            match unmatched_new
+                .iter()
+                .position(|n| (n.time, n.track) == (old.time, old.track))
+            {
+                Some(index) => {
+                    let new = unmatched_new.remove(index);
+                    if old.sound_id != new.sound_id {
+                        diff.moved.push((old.clone(), new.clone()));
+                    }
                 }
+                None => diff.removed.push(old.clone()),
             }
         }
+        diff.added = unmatched_new.into_iter().cloned().collect();
+
+        (diff.bpm_added, diff.bpm_removed) = list_diff(&self.bpm, &other.bpm);
+        (diff.meter_added, diff.meter_removed) = list_diff(&self.beat_per_bar, &other.beat_per_bar);
+
+        diff
+    }
+
+    /// Get a list of tempo and meter changes, sorted by time.
+    ///
+    /// A BPM change and a meter change on the same time are merged into one entry.
+    pub fn tempo_map(&self) -> Vec<TempoMapEntry> {
+        let mut times: Vec<u32> = self
+            .bpm
+            .iter()
+            .map(|b| b.time)
+            .chain(self.beat_per_bar.iter().map(|b| b.time))
+            .collect();
+        times.sort_unstable();
+        times.dedup();
+
+        let end = times.last().copied().unwrap_or(0);
+        let boundaries = self.measure_boundaries_until(end);
+
+        times
+            .into_iter()
+            .map(|time| {
+                let bar = boundaries.partition_point(|b| *b <= time) - 1;
+                let beat = if self.note_tick == 0 {
+                    0.0
+                } else {
+                    (time - boundaries[bar]) as f64 / self.note_tick as f64
+                };
+
+                TempoMapEntry {
+                    time,
+                    bar: bar as u32 + 1,
+                    beat: beat + 1.0,
+                    seconds: self.tick_to_seconds(time),
+                    bpm: self.bpm.iter().find(|b| b.time == time).map(|b| b.value),
+                    beat_per_bar: self
+                        .beat_per_bar
+                        .iter()
+                        .find(|b| b.time == time)
+                        .map(|b| b.value),
+                }
+            })
+            .collect()
+    }
+
+    /// Get a count of audio frames from 0 to the last note, rounded up. (In `audio_sample_rate`)
+    ///
+    /// If there are no notes, it returns `0`.
+    pub fn total_samples(&self) -> u64 {
+        let Some(last) = self.notes.iter().map(|n| n.time).max() else {
+            return 0;
+        };
+        (self.tick_to_seconds(last) * f64::from(self.audio_sample_rate)).ceil() as u64
+    }
+
+    /// Get the length of the soundmap in seconds, from 0 to the last note. BPM changes are
+    /// applied in time order.
+    ///
+    /// If there are no notes, it returns `0.0`.
+    pub fn duration_seconds(&self) -> f64 {
+        self.notes
+            .iter()
+            .map(|n| n.time)
+            .max()
+            .map_or(0.0, |last| self.tick_to_seconds(last))
+    }
+
+    /// Get seconds from the first note to the last note. It is useful for actual gameplay
+    /// length, because silence before the first note is excluded.
+    ///
+    /// If there are no notes, it returns `0.0`.
+    pub fn active_duration_seconds(&self) -> f64 {
+        let first = self.notes.iter().map(|n| n.time).min();
+        let last = self.notes.iter().map(|n| n.time).max();
+
+        match (first, last) {
+            (Some(first), Some(last)) => self.tick_to_seconds(last) - self.tick_to_seconds(first),
+            _ => 0.0,
+        }
+    }
+
+    /// Summarize BPM of the soundmap.
+    ///
+    /// The average weights each tempo segment by its duration in ticks. The last segment lasts
+    /// until the last note. If the BPM list is empty, all values are the default 120.
+    pub fn bpm_summary(&self) -> BpmSummary {
+        let mut bpm: Vec<&Bpm> = self.bpm.iter().collect();
+        bpm.sort_by_key(|b| b.time);
+
+        let (Some(first), Some(last)) = (bpm.first(), bpm.last()) else {
+            let default = Bpm::default().value;
+            return BpmSummary {
+                min: default,
+                max: default,
+                average_time_weighted: default,
+            };
+        };
+
+        let min = bpm.iter().map(|b| b.value).fold(f64::INFINITY, f64::min);
+        let max = bpm
+            .iter()
+            .map(|b| b.value)
+            .fold(f64::NEG_INFINITY, f64::max);
+
+        let last_note_time = self.notes.iter().map(|n| n.time).max().unwrap_or(0);
+        let end = last_note_time.max(last.time);
+
+        let mut weighted_sum = 0.0;
+        let mut total_ticks = 0u64;
+        for (index, segment) in bpm.iter().enumerate() {
+            let segment_end = bpm.get(index + 1).map_or(end, |next| next.time);
+            let ticks = u64::from(segment_end.saturating_sub(segment.time));
+            weighted_sum += segment.value * ticks as f64;
+            total_ticks += ticks;
+        }
+
+        // No duration to weight, so it starts with the first tempo
+        let average_time_weighted = if total_ticks == 0 {
+            first.value
+        } else {
+            weighted_sum / total_ticks as f64
+        };
+
+        BpmSummary {
+            min,
+            max,
+            average_time_weighted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_tag_from_instrument_str() {
+        let tag = TrackTag::with_instrument_str(1, "Lead", "electric guitar");
+        assert_eq!(tag.id, 1);
+        assert_eq!(tag.name, "Lead");
+        assert!(matches!(tag.instrument, Instrument::EGui));
+
+        assert!(matches!(
+            Instrument::from_loose_str("Hi-Hat"),
+            Some(Instrument::HiHat)
+        ));
+        assert!(Instrument::from_loose_str("theremin").is_none());
+        let unknown = TrackTag::with_instrument_str(2, "Solo", "theremin");
+        assert!(matches!(unknown.instrument, Instrument::SomeElse));
+    }
+
+    #[test]
+    fn numeric_instrument() {
+        let json = serde_json::to_string(&NumericInstrument(Instrument::Kick)).unwrap();
+        assert_eq!(json, "1");
+        let loaded: NumericInstrument = serde_json::from_str(&json).unwrap();
+        assert!(matches!(loaded.0, Instrument::Kick));
+
+        let vox: NumericInstrument = serde_json::from_str("15").unwrap();
+        assert!(matches!(vox.0, Instrument::Vox));
+        assert!(serde_json::from_str::<NumericInstrument>("16").is_err());
+
+        // The string form is still default
+        assert_eq!(
+            serde_json::to_string(&Instrument::Kick).unwrap(),
+            "\"Kick\""
+        );
+    }
+
+    #[test]
+    fn used_instruments() {
+        let mut soundmap = SoundMap::from_notes([(0, 0, 1), (1, 0, 0), (0, 192, 1), (2, 0, 3)]);
+        soundmap.set_note_track(0, "Kick", Instrument::Kick);
+        soundmap.set_note_track(1, "Snare", Instrument::Snare);
+        soundmap.set_note_track(2, "Unused", Instrument::Pno);
+        soundmap.set_note_track(4, "Other Snare", Instrument::Snare);
+        soundmap.insert_note(3, 384, 4);
+
+        assert_eq!(
+            soundmap.used_instruments(),
+            vec![Instrument::Snare, Instrument::Kick]
+        );
+        assert!(SoundMap::new().used_instruments().is_empty());
+    }
+
+    #[test]
+    fn untagged_tracks() {
+        let mut soundmap = SoundMap::new();
+        soundmap.set_note_track(0, "Drums", Instrument::Kick);
+        soundmap.insert_note(0, 0, 0);
+        soundmap.insert_note(1, 0, 3);
+        soundmap.insert_note(1, 192, 3);
+
+        assert_eq!(soundmap.untagged_tracks(), vec![3]);
+
+        soundmap.add_missing_track_tags();
+        assert!(soundmap.untagged_tracks().is_empty());
+        assert_eq!(soundmap.track_tags.len(), 2);
+        assert_eq!(soundmap.track_tags[1].id, 3);
+    }
+
+    #[test]
+    fn tick_to_seconds() {
+        let mut soundmap = SoundMap::new();
+        assert_eq!(soundmap.tick_to_seconds(768), 2.0);
+
+        // Doubled from tick 768, and the list is not sorted
+        soundmap.bpm.push(Bpm::new(240.0, 768));
+        soundmap.bpm.push(Bpm::new(120.0, 0));
+        assert_eq!(soundmap.tick_to_seconds(768), 2.0);
+        assert_eq!(soundmap.tick_to_seconds(1536), 3.0);
+
+        soundmap.note_tick = 0;
+        assert_eq!(soundmap.tick_to_seconds(1536), 0.0);
+    }
+
+    #[test]
+    fn tempo_at_tick() {
+        let mut soundmap = SoundMap::new();
+        soundmap.bpm.clear();
+        soundmap.beat_per_bar.clear();
+        assert_eq!(soundmap.bpm_at(0), 120.0);
+        assert_eq!(soundmap.beat_per_bar_at(0), BeatPerBar::default().value);
+
+        soundmap.bpm.push(Bpm::new(180.0, 768));
+        soundmap.bpm.push(Bpm::new(140.0, 192));
+        soundmap.beat_per_bar.push(BeatPerBar::new(3, 768));
+        soundmap.beat_per_bar.push(BeatPerBar::new(5, 192));
+
+        // Before the first change
+        assert_eq!(soundmap.bpm_at(0), 140.0);
+        assert_eq!(soundmap.beat_per_bar_at(0), 5);
+        // On a change
+        assert_eq!(soundmap.bpm_at(768), 180.0);
+        assert_eq!(soundmap.beat_per_bar_at(768), 3);
+        assert_eq!(soundmap.bpm_at(767), 140.0);
+        // After the last change
+        assert_eq!(soundmap.bpm_at(10000), 180.0);
+        assert_eq!(soundmap.beat_per_bar_at(10000), 3);
+    }
+
+    #[test]
+    fn final_bpm() {
+        let mut soundmap = SoundMap::new();
+        soundmap.bpm.clear();
+        assert_eq!(soundmap.final_bpm(), 120.0);
+
+        soundmap.bpm.push(Bpm::new(180.0, 768));
+        soundmap.bpm.push(Bpm::new(140.0, 0));
+        soundmap.bpm.push(Bpm::new(200.0, 3072));
+        soundmap.insert_note(0, 0, 0);
+        soundmap.insert_note(0, 1536, 0);
+        assert_eq!(soundmap.final_bpm(), 180.0);
+    }
+
+    #[test]
+    fn duration_seconds() {
+        let mut soundmap = SoundMap::new();
+        assert_eq!(soundmap.duration_seconds(), 0.0);
+
+        soundmap.insert_note(0, 384, 0);
+        soundmap.insert_note(0, 768, 0);
+        assert_eq!(soundmap.duration_seconds(), 2.0);
+
+        // 240 BPM after 2 beats
+        soundmap.bpm.push(Bpm::new(240.0, 384));
+        assert_eq!(soundmap.duration_seconds(), 1.5);
+    }
+
+    #[test]
+    fn active_duration_seconds() {
+        let mut soundmap = SoundMap::new();
+        assert_eq!(soundmap.active_duration_seconds(), 0.0);
+
+        soundmap.insert_note(0, 768, 0);
+        soundmap.insert_note(0, 1536, 0);
+
+        // 768 ticks = 4 beats in 120 BPM
+        assert_eq!(soundmap.active_duration_seconds(), 2.0);
+        assert_eq!(soundmap.tick_to_seconds(1536), 4.0);
+    }
+
+    #[test]
+    fn validated_deserialize() {
+        let mut soundmap = SoundMap::new();
+        soundmap.bpm.insert(0, Bpm::new(180.0, 768));
+        soundmap.insert_note(0, 0, 0);
+        let json = serde_json::to_string(&soundmap).unwrap();
+
+        // BPM list is sorted on load
+        let validated: ValidatedSoundMap = serde_json::from_str(&json).unwrap();
+        assert_eq!(validated.0.bpm[0].time, 0);
+        assert_eq!(validated.0.bpm[1].time, 768);
+
+        soundmap.notes.push(soundmap.notes[0].clone());
+        let json = serde_json::to_string(&soundmap).unwrap();
+        assert!(serde_json::from_str::<ValidatedSoundMap>(&json).is_err());
+        assert!(serde_json::from_str::<SoundMap>(&json).is_ok());
+
+        let mut soundmap = SoundMap::new().with_bpm(0.0);
+        assert!(soundmap.validate_and_normalize().is_err());
+        let mut soundmap = SoundMap::new().with_beat_per_bar(0);
+        assert!(soundmap.validate_and_normalize().is_err());
+    }
+
+    #[test]
+    fn get_and_remove_note() {
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(0, 0, 0);
+        soundmap.insert_note(1, 192, 0);
+        soundmap.insert_note(2, 384, 0);
+
+        assert_eq!(soundmap.get_note(1).unwrap().time, 192);
+        soundmap.get_note_mut(2).unwrap().time = 576;
+        assert_eq!(soundmap.get_note(2).unwrap().time, 576);
+
+        let removed = soundmap.remove_note(1).unwrap();
+        assert_eq!(removed.sound_id, 1);
+        assert!(soundmap.get_note(1).is_none());
+        assert!(soundmap.remove_note(1).is_none());
+
+        // Ids are not reindexed
+        let ids: Vec<u16> = soundmap.notes.iter().map(|n| n.id).collect();
+        assert_eq!(ids, vec![0, 2]);
+        assert_eq!(soundmap.get_note(2).unwrap().sound_id, 2);
+
+        // The removed id is used again
+        assert_eq!(soundmap.insert_note(3, 768, 0), Some(1));
+    }
+
+    #[test]
+    fn insert_note_when_ids_are_used() {
+        let notes = (0..=u32::from(u16::MAX)).map(|time| (0, time, 0));
+        let mut soundmap = SoundMap::from_notes(notes);
+        assert_eq!(soundmap.insert_note(0, 0, 0), None);
+        assert_eq!(soundmap.notes.len(), usize::from(u16::MAX) + 1);
+    }
+
+    #[test]
+    fn ensure_tempo() {
+        let mut soundmap = SoundMap::from_notes([(0, 0, 0)]);
+        soundmap.bpm.clear();
+        assert!(soundmap.ensure_tempo());
+        assert_eq!(soundmap.bpm, vec![Bpm::new(120.0, 0)]);
+        assert!(!soundmap.ensure_tempo());
+        assert_eq!(soundmap.bpm.len(), 1);
+    }
+
+    #[test]
+    fn align_bpm_to_grid() {
+        let mut soundmap = SoundMap::new();
+        soundmap.bpm.push(Bpm::new(150.0, 770));
+        soundmap.align_bpm_to_grid(1);
+        assert_eq!(soundmap.bpm.len(), 2);
+        assert_eq!(soundmap.bpm[1].time, 768);
+
+        // Collided after snapping
+        soundmap.bpm.push(Bpm::new(160.0, 800));
+        soundmap.bpm.push(Bpm::new(180.0, 1000));
+        soundmap.align_bpm_to_grid(1);
+        assert_eq!(soundmap.bpm.len(), 3);
+        assert_eq!(soundmap.bpm[1].time, 768);
+        assert_eq!(soundmap.bpm[1].value, 160.0);
+        assert_eq!(soundmap.bpm[2].time, 960);
+
+        // The nearest grid tick is after u32::MAX
+        soundmap.bpm.push(Bpm::new(200.0, u32::MAX - 1));
+        soundmap.align_bpm_to_grid(4);
+        assert_eq!(soundmap.bpm.last().unwrap().time, u32::MAX / 48 * 48);
+    }
+
+    #[test]
+    fn chords() {
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(0, 100, 0);
+        soundmap.insert_note(1, 0, 1);
+        soundmap.insert_note(2, 1, 2);
+        soundmap.insert_note(3, 2, 3);
+        soundmap.insert_note(4, 3, 0);
+
+        let chords = soundmap.chords(2);
+        let sound_ids: Vec<Vec<u16>> = chords
+            .iter()
+            .map(|c| c.iter().map(|n| n.sound_id).collect())
+            .collect();
+        assert_eq!(sound_ids, vec![vec![1, 2, 3], vec![4], vec![0]]);
+
+        assert_eq!(soundmap.chords(0).len(), 5);
+    }
+
+    #[test]
+    fn suspicious_default_notes() {
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(0, 0, 0);
+        soundmap.insert_note(1, 0, 0);
+        soundmap.insert_note(0, 192, 0);
+        // Only one note on 0 is normal
+        assert!(soundmap.suspicious_default_notes().is_empty());
+
+        soundmap.insert_note(0, 0, 0);
+        soundmap.insert_note(0, 0, 0);
+        let ids: Vec<u16> = soundmap
+            .suspicious_default_notes()
+            .iter()
+            .map(|n| n.id)
+            .collect();
+        assert_eq!(ids, vec![0, 3, 4]);
+    }
+
+    #[test]
+    fn sort_notes() {
+        let mut soundmap = SoundMap::from_notes([(0, 192, 1), (1, 0, 2), (2, 192, 0), (3, 0, 2)]);
+        assert!(!soundmap.is_sorted());
+
+        soundmap.sort_notes();
+        assert!(soundmap.is_sorted());
+        let notes: Vec<(u16, u16)> = soundmap.notes.iter().map(|n| (n.id, n.sound_id)).collect();
+        // Ids are kept, and same time and track keep the order
+        assert_eq!(notes, vec![(1, 1), (3, 3), (2, 2), (0, 0)]);
+
+        soundmap.bpm.push(Bpm::new(150.0, 768));
+        soundmap.bpm.push(Bpm::new(140.0, 384));
+        soundmap.beat_per_bar.push(BeatPerBar::new(3, 768));
+        soundmap.beat_per_bar.push(BeatPerBar::new(4, 384));
+        soundmap.notes.reverse();
+        soundmap.sort_all();
+        assert!(soundmap.is_sorted());
+        let bpm: Vec<u32> = soundmap.bpm.iter().map(|b| b.time).collect();
+        assert_eq!(bpm, vec![0, 384, 768]);
+        let meters: Vec<u32> = soundmap.beat_per_bar.iter().map(|b| b.time).collect();
+        assert_eq!(meters, vec![0, 384, 768]);
+    }
+
+    #[test]
+    fn silence_gaps() {
+        let mut soundmap = SoundMap::new();
+        for time in [2500, 0, 192, 500] {
+            soundmap.insert_note(0, time, 0);
+        }
+        assert_eq!(soundmap.silence_gaps(1000), vec![(500, 2500)]);
+        assert_eq!(soundmap.silence_gaps(2000), Vec::new());
+        assert!(SoundMap::new().silence_gaps(0).is_empty());
+    }
+
+    #[test]
+    fn max_polyphony() {
+        let mut soundmap = SoundMap::new();
+        assert_eq!(soundmap.max_polyphony(96), 0);
+
+        for time in [0, 40, 80, 200] {
+            soundmap.insert_note(0, time, 0);
+        }
+        assert_eq!(soundmap.max_polyphony(96), 3);
+        // A note ends when the next one starts
+        assert_eq!(soundmap.max_polyphony(40), 1);
+        assert_eq!(soundmap.max_polyphony(0), 1);
+    }
+
+    #[test]
+    fn pulses_ms() {
+        let mut soundmap = SoundMap::new();
+        soundmap.insert_note(0, 192 * 8, 0);
+
+        // 120 BPM
+        let beats = soundmap.beat_pulses_ms();
+        assert_eq!(beats.len(), 9);
+        for (index, beat) in beats.iter().enumerate() {
+            assert_eq!(*beat, index as f64 * 500.0);
+        }
+        assert_eq!(soundmap.bar_pulses_ms(), vec![0.0, 2000.0, 4000.0]);
+
+        // A note on u32::MAX
+        let mut soundmap = SoundMap::new();
+        soundmap.note_tick = u16::MAX;
+        soundmap.insert_note(0, u32::MAX, 0);
+        let beats = soundmap.beat_pulses_ms();
+        assert_eq!(beats.len() as u32, u32::MAX / u32::from(u16::MAX) + 1);
+    }
+
+    #[test]
+    fn from_notes() {
+        let soundmap = SoundMap::from_notes([(0, 0, 0), (1, 192, 0), (0, 384, 1)]);
+        assert_eq!(soundmap.notes.len(), 3);
+        let ids: Vec<u16> = soundmap.notes.iter().map(|n| n.id).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(soundmap.notes[2].track, 1);
+        assert_eq!(soundmap.note_tick, RECOMMENDED_NOTE_TICK);
+
+        // Every id is used
+        let full = SoundMap::from_notes((0..=u32::from(u16::MAX)).map(|time| (0, time, 0)));
+        assert_eq!(full.notes.last().unwrap().id, u16::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many notes")]
+    fn from_too_many_notes() {
+        SoundMap::from_notes((0..=u32::from(u16::MAX) + 1).map(|time| (0, time, 0)));
+    }
+
+    #[test]
+    fn time_gcd() {
+        let mut soundmap = SoundMap::from_notes([(0, 0, 0)]);
+        assert_eq!(soundmap.time_gcd(), 0);
+
+        for time in [192, 288, 480] {
+            soundmap.insert_note(0, time, 0);
+        }
+        assert_eq!(soundmap.time_gcd(), 96);
+    }
+
+    #[test]
+    fn total_samples() {
+        let mut soundmap = SoundMap::new().with_audio_sample_rate(48000);
+        assert_eq!(soundmap.total_samples(), 0);
+
+        // 2 beats in 120 BPM
+        soundmap.insert_note(0, 384, 0);
+        assert_eq!(soundmap.total_samples(), 48000);
+    }
+
+    #[test]
+    fn diff() {
+        let old = SoundMap::from_notes([(0, 0, 0), (1, 192, 0), (2, 384, 1)]);
+        let mut new = old.clone();
+        assert!(old.diff(&new).is_empty());
+
+        new.notes[1].time = 288;
+        new.bpm.push(Bpm::new(150.0, 768));
+        let diff = old.diff(&new);
+        assert_eq!(diff.moved.len(), 1);
+        assert_eq!((diff.moved[0].0.time, diff.moved[0].1.time), (192, 288));
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(diff.bpm_added, vec![Bpm::new(150.0, 768)]);
+        assert!(diff.meter_added.is_empty());
+
+        // Renumbered notes are matched by time and track
+        let mut renumbered = old.clone();
+        renumbered.notes[2].id = 10;
+        renumbered.notes.push(Note {
+            id: 11,
+            sound_id: 3,
+            time: 576,
+            track: 0,
+        });
+        let diff = old.diff(&renumbered);
+        assert!(diff.moved.is_empty() && diff.removed.is_empty());
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].time, 576);
+    }
+
+    #[test]
+    fn clamp_notes_to() {
+        let mut soundmap = SoundMap::from_notes([(0, 0, 0), (0, 1_000_000, 0), (0, 4800, 0)]);
+        let mut clamped = soundmap.clone();
+
+        assert_eq!(soundmap.clamp_notes_to(5000, true), 1);
+        assert_eq!(soundmap.notes.len(), 2);
+        assert!(soundmap.notes.iter().all(|n| n.time <= 5000));
+
+        assert_eq!(clamped.clamp_notes_to(5000, false), 1);
+        assert_eq!(clamped.notes[1].time, 5000);
+        assert_eq!(clamped.clamp_notes_to(5000, false), 0);
+    }
+
+    #[test]
+    fn trim() {
+        let mut soundmap =
+            SoundMap::from_notes([(0, 0, 0), (1, 768, 0), (2, 1000, 0), (3, 1536, 0)]);
+        soundmap.bpm = vec![
+            Bpm::new(120.0, 0),
+            Bpm::new(150.0, 384),
+            Bpm::new(180.0, 1200),
+        ];
+        soundmap.beat_per_bar = vec![BeatPerBar::new(4, 0), BeatPerBar::new(3, 2000)];
+
+        soundmap.trim(768, 1536);
+        let notes: Vec<(u16, u32)> = soundmap
+            .notes
+            .iter()
+            .map(|n| (n.sound_id, n.time))
+            .collect();
+        assert_eq!(notes, vec![(1, 0), (2, 232)]);
+        assert_eq!(soundmap.bpm, vec![Bpm::new(150.0, 0), Bpm::new(180.0, 432)]);
+        assert_eq!(soundmap.beat_per_bar, vec![BeatPerBar::new(4, 0)]);
+
+        // If changes start after `start`, the first one is used (Same as `bpm_at`)
+        let mut soundmap = SoundMap::from_notes([(0, 768, 0)]);
+        soundmap.bpm = vec![Bpm::new(150.0, 1000)];
+        soundmap.beat_per_bar = vec![BeatPerBar::new(3, 1000)];
+        soundmap.trim(768, 1536);
+        assert_eq!(soundmap.bpm, vec![Bpm::new(150.0, 0), Bpm::new(150.0, 232)]);
+        assert_eq!(
+            soundmap.beat_per_bar,
+            vec![BeatPerBar::new(3, 0), BeatPerBar::new(3, 232)]
+        );
+    }
+
+    #[test]
+    fn swap_tracks() {
+        let mut soundmap = SoundMap::from_notes([(0, 0, 0), (1, 192, 1), (2, 384, 1)]);
+        soundmap.set_note_track(0, "Kick", Instrument::Kick);
+        soundmap.set_note_track(1, "Snare", Instrument::Snare);
+
+        soundmap.swap_tracks(0, 1);
+        let tracks: Vec<u16> = soundmap.notes.iter().map(|n| n.track).collect();
+        assert_eq!(tracks, vec![1, 0, 0]);
+        let kick = soundmap
+            .track_tags
+            .iter()
+            .find(|t| t.name == "Kick")
+            .unwrap();
+        assert_eq!(kick.id, 1);
+
+        // Absent track
+        soundmap.swap_tracks(0, 7);
+        assert_eq!(soundmap.notes[0].track, 1);
+    }
+
+    #[test]
+    fn measure_boundaries() {
+        let mut soundmap = SoundMap::new();
+        assert_eq!(soundmap.measure_boundaries(), vec![0]);
+
+        soundmap.beat_per_bar.push(BeatPerBar::new(3, 1536));
+        soundmap.insert_note(0, 2200, 0);
+
+        // 4/4 for 2 measures, and 3/4
+        assert_eq!(soundmap.measure_boundaries(), vec![0, 768, 1536, 2112]);
+
+        // Bars until the end of u32
+        let mut soundmap = SoundMap::new();
+        soundmap.note_tick = u16::MAX;
+        soundmap.insert_note(0, u32::MAX, 0);
+        let boundaries = soundmap.measure_boundaries();
+        let bar_ticks = 4 * u32::from(u16::MAX);
+        assert_eq!(boundaries.len() as u32, u32::MAX / bar_ticks + 1);
+        assert_eq!(
+            *boundaries.last().unwrap(),
+            u32::MAX / bar_ticks * bar_ticks
+        );
+    }
+
+    #[test]
+    fn bpm_summary() {
+        let mut soundmap = SoundMap::new();
+        soundmap.bpm.push(Bpm::new(180.0, 768));
+        soundmap.insert_note(0, 0, 0);
+        soundmap.insert_note(0, 3072, 0);
+
+        let summary = soundmap.bpm_summary();
+        assert_eq!(summary.min, 120.0);
+        assert_eq!(summary.max, 180.0);
+        // (120 * 768 + 180 * 2304) / 3072
+        assert_eq!(summary.average_time_weighted, 165.0);
+    }
+
+    #[test]
+    fn bpm_summary_empty() {
+        let mut soundmap = SoundMap::new();
+        soundmap.bpm.clear();
+
+        let summary = soundmap.bpm_summary();
+        assert_eq!(summary.min, 120.0);
+        assert_eq!(summary.max, 120.0);
+        assert_eq!(summary.average_time_weighted, 120.0);
     }
 }